@@ -0,0 +1,504 @@
+pub mod noise;
+pub mod recorder;
+pub mod square;
+pub mod wave;
+
+use std::path::Path;
+
+use crate::{
+    apu::{noise::NoiseChannel, recorder::AudioRecorder, square::SquareChannel, wave::WaveChannel},
+    quartz::CLOCK_FREQUENCY,
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{error_type::Errors, u8_traits::Bit},
+};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+// Samples not consumed by a frontend are dropped past this point
+const MAX_BUFFERED_SAMPLES: usize = 1 << 16;
+// The frame sequencer is clocked by the falling edge of DIV bit 4
+const FRAME_SEQUENCER_DIV_BIT: u8 = 12;
+
+/// Sound channel, registers are given as an index 0-4 (NRx0 to NRx4)
+pub trait Channel {
+    fn write_register(&mut self, index: u8, value: u8);
+    /// Called every clock tick
+    fn next_tick(&mut self);
+    /// Digital output 0-15
+    fn output(&self) -> u8;
+    fn is_enabled(&self) -> bool;
+    fn is_dac_enabled(&self) -> bool;
+    fn clock_length(&mut self);
+    fn clock_envelope(&mut self) {}
+    fn clock_sweep(&mut self) {}
+}
+
+/// Length timer shutting the channel down when it reaches 0
+pub struct LengthCounter {
+    max: u16,
+    counter: u16,
+    pub enabled: bool,
+}
+impl LengthCounter {
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+    // Return true when the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+// The maximum is fixed by the channel
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.counter = reader.read_u16()?;
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// Volume envelope of NRx2
+#[derive(Default)]
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    pub volume: u8,
+}
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope::default()
+    }
+    pub fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value.get_bit(3);
+        self.period = value & 0x07;
+    }
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+    pub fn clock(&mut self) {
+        if self.period == 0 || self.timer == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period;
+            match self.increase {
+                true if self.volume < 15 => self.volume += 1,
+                false if self.volume > 0 => self.volume -= 1,
+                _ => (),
+            }
+        }
+    }
+}
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial_volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.period);
+        writer.write_u8(self.timer);
+        writer.write_u8(self.volume);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.initial_volume = reader.read_u8()?;
+        self.increase = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// Capacitor removing the dc offset of the output, like the real hardware
+struct HighPassFilter {
+    charge: f32,
+    factor: f32,
+}
+impl HighPassFilter {
+    fn new(sample_rate: u32) -> HighPassFilter {
+        HighPassFilter {
+            charge: 0.0,
+            factor: 0.999958f32.powf(CLOCK_FREQUENCY as f32 / sample_rate as f32),
+        }
+    }
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.charge;
+        self.charge = input - output * self.factor;
+        output
+    }
+}
+
+/// Audio processing unit mapped at 0xFF10-0xFF3F
+pub struct APU {
+    pub square1: SquareChannel,
+    pub square2: SquareChannel,
+    pub wave: WaveChannel,
+    pub noise: NoiseChannel,
+    power: bool,
+    // Raw value of the registers 0xFF10-0xFF2F as last written
+    registers: [u8; 0x20],
+    frame_sequencer_step: u8,
+    last_div_bit: bool,
+    sample_rate: u32,
+    sample_counter: u32,
+    // Left and right filter of each channel
+    filters: [(HighPassFilter, HighPassFilter); 4],
+    // Stereo samples (left, right) between -1.0 and 1.0
+    samples: Vec<(f32, f32)>,
+    recorder: Option<AudioRecorder>,
+}
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl APU {
+    pub fn new() -> APU {
+        APU {
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            power: false,
+            registers: [0u8; 0x20],
+            frame_sequencer_step: 0,
+            last_div_bit: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_counter: 0,
+            filters: channel_filters(DEFAULT_SAMPLE_RATE),
+            samples: vec![],
+            recorder: None,
+        }
+    }
+    /// Power on state, the sample rate and the recording are kept
+    pub fn reset(&mut self) {
+        let recorder = self.recorder.take();
+        let sample_rate = self.sample_rate;
+        *self = APU::new();
+        self.set_sample_rate(sample_rate);
+        self.recorder = recorder;
+    }
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_counter = 0;
+        self.filters = channel_filters(sample_rate);
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    /// Samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        std::mem::take(&mut self.samples)
+    }
+    /// Record every sample produced to a 16-bit stereo wav file until `stop_recording`
+    pub fn start_recording(&mut self, path: &Path, per_channel: bool) -> Result<(), Errors> {
+        self.stop_recording()?;
+        self.recorder = Some(AudioRecorder::create(path, self.sample_rate, per_channel)?);
+        Ok(())
+    }
+    pub fn stop_recording(&mut self) -> Result<(), Errors> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+    pub fn is_powered(&self) -> bool {
+        self.power
+    }
+    fn channels(&self) -> [&dyn Channel; 4] {
+        [&self.square1, &self.square2, &self.wave, &self.noise]
+    }
+    fn channels_mut(&mut self) -> [&mut dyn Channel; 4] {
+        [
+            &mut self.square1,
+            &mut self.square2,
+            &mut self.wave,
+            &mut self.noise,
+        ]
+    }
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                let mut value = (self.power as u8) << 7;
+                for (i, channel) in self.channels().iter().enumerate() {
+                    value.set_bit(i as u8, channel.is_enabled());
+                }
+                value
+            }
+            0xFF30..=0xFF3F => self.wave.read_wave_ram(address),
+            _ => self.registers[(address - 0xFF10) as usize],
+        }
+    }
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF26 => self.set_power(value.get_bit(7)),
+            0xFF30..=0xFF3F => self.wave.write_wave_ram(address, value),
+            // Registers are read only while the apu is off
+            _ if !self.power => (),
+            0xFF10..=0xFF23 => {
+                self.registers[(address - 0xFF10) as usize] = value;
+                let offset = (address - 0xFF10) as u8;
+                self.channels_mut()[(offset / 5) as usize].write_register(offset % 5, value);
+            }
+            0xFF24..=0xFF25 => self.registers[(address - 0xFF10) as usize] = value,
+            _ => (),
+        }
+    }
+    fn set_power(&mut self, power: bool) {
+        if self.power && !power {
+            // Turning the apu off clears every register but the wave ram
+            for address in 0xFF10..=0xFF25 {
+                self.write_register(address, 0x00);
+            }
+            let wave_ram = self.wave.wave_ram;
+            self.square1 = SquareChannel::new(true);
+            self.square2 = SquareChannel::new(false);
+            self.wave = WaveChannel::new();
+            self.wave.wave_ram = wave_ram;
+            self.noise = NoiseChannel::new();
+        }
+        if !self.power && power {
+            self.frame_sequencer_step = 0;
+        }
+        self.power = power;
+    }
+    /// Called every clock tick with the internal counter of the timer (DIV)
+    pub fn next_tick(&mut self, div_counter: u16) {
+        let div_bit = (div_counter >> FRAME_SEQUENCER_DIV_BIT) & 1 == 1;
+        if self.power {
+            if self.last_div_bit && !div_bit {
+                self.clock_frame_sequencer();
+            }
+            for channel in self.channels_mut() {
+                channel.next_tick();
+            }
+        }
+        self.last_div_bit = div_bit;
+
+        self.sample_counter += self.sample_rate;
+        if self.sample_counter >= CLOCK_FREQUENCY {
+            self.sample_counter -= CLOCK_FREQUENCY;
+            let channels = self.mix_channels();
+            let sample = channels.iter().fold((0.0, 0.0), |(left, right), channel| {
+                (left + channel.0, right + channel.1)
+            });
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(sample, &channels);
+            }
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(sample);
+            }
+        }
+    }
+    // 512Hz sequencer clocking the length (256Hz), sweep (128Hz) and envelope (64Hz) units
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        for channel in self.channels_mut() {
+            if step.is_multiple_of(2) {
+                channel.clock_length();
+            }
+            if step == 2 || step == 6 {
+                channel.clock_sweep();
+            }
+            if step == 7 {
+                channel.clock_envelope();
+            }
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+    /// Analog output of each channel between -1.0 and 1.0, 0.0 when its DAC is off
+    pub fn channel_outputs(&self) -> [f32; 4] {
+        self.channels()
+            .map(|channel| match channel.is_dac_enabled() {
+                true => 1.0 - channel.output() as f32 / 7.5,
+                false => 0.0,
+            })
+    }
+    // Stereo output of each channel with the panning of NR51 and master volume of NR50
+    // the sum of the four being the mixed output
+    fn mix_channels(&mut self) -> [(f32, f32); 4] {
+        if !self.power {
+            return [(0.0, 0.0); 4];
+        }
+        let nr50 = self.registers[0x14];
+        let nr51 = self.registers[0x15];
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        let outputs = self.channel_outputs();
+        let mut channels = [(0.0, 0.0); 4];
+        for (i, (left_filter, right_filter)) in self.filters.iter_mut().enumerate() {
+            let output = outputs[i] / 4.0;
+            let left = match nr51.get_bit(i as u8 + 4) {
+                true => output * left_volume / 8.0,
+                false => 0.0,
+            };
+            let right = match nr51.get_bit(i as u8) {
+                true => output * right_volume / 8.0,
+                false => 0.0,
+            };
+            channels[i] = (left_filter.filter(left), right_filter.filter(right));
+        }
+        channels
+    }
+}
+
+fn channel_filters(sample_rate: u32) -> [(HighPassFilter, HighPassFilter); 4] {
+    std::array::from_fn(|_| {
+        (
+            HighPassFilter::new(sample_rate),
+            HighPassFilter::new(sample_rate),
+        )
+    })
+}
+
+// The sample rate, the buffered samples and the recording belong to the host
+impl SaveState for APU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.write_bool(self.power);
+        writer.write_bytes(&self.registers);
+        writer.write_u8(self.frame_sequencer_step);
+        writer.write_bool(self.last_div_bit);
+        writer.write_u32(self.sample_counter);
+        for (left, right) in &self.filters {
+            writer.write_f32(left.charge);
+            writer.write_f32(right.charge);
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.power = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.registers)?;
+        self.frame_sequencer_step = reader.read_u8()?;
+        self.last_div_bit = reader.read_bool()?;
+        self.sample_counter = reader.read_u32()?;
+        for (left, right) in self.filters.iter_mut() {
+            left.charge = reader.read_f32()?;
+            right.charge = reader.read_f32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{APU, CLOCK_FREQUENCY};
+
+    // Apu powered on, channel 1 playing on both sides at full volume
+    fn playing_apu() -> APU {
+        let mut apu = APU::new();
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF24, 0x77);
+        apu.write_register(0xFF25, 0x11);
+        apu.write_register(0xFF11, 0x80);
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF13, 0x00);
+        apu.write_register(0xFF14, 0x87);
+        apu
+    }
+
+    #[test]
+    fn power_test() {
+        let mut apu = APU::new();
+        apu.write_register(0xFF12, 0xF0);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        assert_eq!(apu.read_register(0xFF26), 0x00);
+        apu.write_register(0xFF30, 0x12);
+        let mut apu = playing_apu();
+        assert_eq!(apu.read_register(0xFF26), 0x81);
+        assert_eq!(apu.read_register(0xFF12), 0xF0);
+        apu.write_register(0xFF3F, 0x34);
+        apu.write_register(0xFF26, 0x00);
+        assert_eq!(apu.read_register(0xFF26), 0x00);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        assert_eq!(apu.read_register(0xFF25), 0x00);
+        assert_eq!(apu.read_register(0xFF3F), 0x34);
+    }
+    #[test]
+    fn length_counter_test() {
+        let mut apu = playing_apu();
+        // Length of 63, enabled
+        apu.write_register(0xFF11, 0x3F);
+        apu.write_register(0xFF14, 0xC7);
+        assert_eq!(apu.read_register(0xFF26), 0x81);
+        // One falling edge of DIV bit 4 clocks the length counter
+        apu.next_tick(0x1000);
+        apu.next_tick(0x0000);
+        assert_eq!(apu.read_register(0xFF26), 0x80);
+    }
+    #[test]
+    fn dac_off_test() {
+        let mut apu = playing_apu();
+        apu.write_register(0xFF12, 0x07);
+        assert_eq!(apu.read_register(0xFF26), 0x80);
+    }
+    #[test]
+    fn sample_rate_test() {
+        let mut apu = playing_apu();
+        apu.set_sample_rate(32_768);
+        for i in 0..CLOCK_FREQUENCY / 8 {
+            apu.next_tick(i as u16);
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 32_768 / 8);
+        assert!(samples
+            .iter()
+            .any(|(left, right)| *left != 0.0 && left == right));
+        assert!(apu.take_samples().is_empty());
+    }
+    #[test]
+    fn recording_test() {
+        let path = env::temp_dir().join("game_boyish_recording_test.wav");
+        let channel_path = env::temp_dir().join("game_boyish_recording_test_ch1.wav");
+        let mut apu = playing_apu();
+        apu.set_sample_rate(32_768);
+        apu.start_recording(&path, true).unwrap();
+        for i in 0..CLOCK_FREQUENCY / 8 {
+            apu.next_tick(i as u16);
+        }
+        apu.stop_recording().unwrap();
+        assert!(!apu.is_recording());
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 4 * 32_768 / 8);
+        assert_eq!(fs::read(&channel_path).unwrap().len(), data.len());
+        for channel in 1..=4 {
+            let channel_path =
+                env::temp_dir().join(format!("game_boyish_recording_test_ch{}.wav", channel));
+            fs::remove_file(channel_path).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    apu::{Channel, Envelope, LengthCounter},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{error_type::Errors, u8_traits::Bit},
+};
+
+/// Channel outputting pseudo random noise from a linear feedback shift register
+pub struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    // 7 bits lfsr instead of 15 bits
+    short_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+    fn period(&self) -> u32 {
+        let divisor = match self.divisor_code {
+            0 => 8,
+            code => code as u32 * 16,
+        };
+        divisor << self.clock_shift
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+    fn shift_lfsr(&mut self) {
+        let xor = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+        self.lfsr = (self.lfsr >> 1) | (xor << 14);
+        if self.short_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+        }
+    }
+}
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Channel for NoiseChannel {
+    fn write_register(&mut self, index: u8, value: u8) {
+        match index {
+            // NR40 doesn't exist
+            0 => (),
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = value & 0xF8 != 0;
+                self.enabled &= self.dac_enabled;
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.short_mode = value.get_bit(3);
+                self.divisor_code = value & 0x07;
+            }
+            _ => {
+                self.length.enabled = value.get_bit(6);
+                if value.get_bit(7) {
+                    self.trigger();
+                }
+            }
+        }
+    }
+    fn next_tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.shift_lfsr();
+        }
+    }
+    fn output(&self) -> u8 {
+        match self.enabled && self.lfsr & 0x01 == 0 {
+            true => self.envelope.volume,
+            false => 0,
+        }
+    }
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    fn is_dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+    fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.short_mode);
+        writer.write_u8(self.divisor_code);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.clock_shift = reader.read_u8()?;
+        self.short_mode = reader.read_bool()?;
+        self.divisor_code = reader.read_u8()?;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NoiseChannel;
+
+    #[test]
+    fn lfsr_test() {
+        let mut channel = NoiseChannel::new();
+        channel.shift_lfsr();
+        assert_eq!(channel.lfsr, 0x3FFF);
+        channel.lfsr = 0x0001;
+        channel.shift_lfsr();
+        assert_eq!(channel.lfsr, 0x4000);
+        // Short mode also copies the result in bit 6
+        channel.short_mode = true;
+        channel.lfsr = 0x0002;
+        channel.shift_lfsr();
+        assert_eq!(channel.lfsr, 0x4041);
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+use crate::util::{error_type::Errors, wav_util::WavWriter};
+
+/// Record the apu output to a wav file, optionally with one more file per channel
+pub struct AudioRecorder {
+    mix: WavWriter,
+    channels: Option<Vec<WavWriter>>,
+    // First write error, recording stops when it happens
+    error: Option<Errors>,
+}
+impl AudioRecorder {
+    /// Channel files are named after the main one, "song.wav" gives "song_ch1.wav"...
+    pub fn create(
+        path: &Path,
+        sample_rate: u32,
+        per_channel: bool,
+    ) -> Result<AudioRecorder, Errors> {
+        let channels = match per_channel {
+            true => Some(
+                (1..=4)
+                    .map(|channel| WavWriter::create(&channel_path(path, channel), sample_rate))
+                    .collect::<Result<Vec<WavWriter>, Errors>>()?,
+            ),
+            false => None,
+        };
+        Ok(AudioRecorder {
+            mix: WavWriter::create(path, sample_rate)?,
+            channels,
+            error: None,
+        })
+    }
+    pub fn record(&mut self, sample: (f32, f32), channels: &[(f32, f32); 4]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write(sample, channels) {
+            self.error = Some(e);
+        }
+    }
+    fn write(&mut self, sample: (f32, f32), channels: &[(f32, f32); 4]) -> Result<(), Errors> {
+        self.mix.write_sample(sample.0, sample.1)?;
+        if let Some(writers) = &mut self.channels {
+            // A channel alone only uses a quarter of the mix range
+            for (writer, (left, right)) in writers.iter_mut().zip(channels) {
+                writer.write_sample(left * 4.0, right * 4.0)?;
+            }
+        }
+        Ok(())
+    }
+    /// Write the wav headers, report the first error that happened while recording
+    pub fn finish(self) -> Result<(), Errors> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.mix.finish()?;
+        for writer in self.channels.into_iter().flatten() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn channel_path(path: &Path, channel: u8) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_ch{}.wav", stem, channel))
+}
@@ -0,0 +1,276 @@
+use crate::{
+    apu::{Channel, Envelope, LengthCounter},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{error_type::Errors, u8_traits::Bit},
+};
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// Frequency sweep of channel 1 (NR10)
+pub struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0x07;
+        self.negate = value.get_bit(3);
+        self.shift = value & 0x07;
+    }
+    // A period of 0 is treated as 8 by the timer
+    fn reload_timer(&mut self) {
+        self.timer = match self.period {
+            0 => 8,
+            period => period,
+        };
+    }
+    // Next frequency, None when it overflows and the channel has to be disabled
+    fn next_frequency(&self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift;
+        let frequency = match self.negate {
+            true => self.shadow_frequency - delta,
+            false => self.shadow_frequency + delta,
+        };
+        match frequency {
+            0..=2047 => Some(frequency),
+            _ => None,
+        }
+    }
+}
+impl SaveState for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.period);
+        writer.write_bool(self.negate);
+        writer.write_u8(self.shift);
+        writer.write_u8(self.timer);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.shadow_frequency);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.period = reader.read_u8()?;
+        self.negate = reader.read_bool()?;
+        self.shift = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.shadow_frequency = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+/// Square wave channel, channel 1 has a frequency sweep channel 2 doesn't
+pub struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+impl SquareChannel {
+    pub fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: with_sweep.then(Sweep::new),
+        }
+    }
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && sweep.next_frequency().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+}
+impl Channel for SquareChannel {
+    fn write_register(&mut self, index: u8, value: u8) {
+        match index {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(value)
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = value & 0xF8 != 0;
+                self.enabled &= self.dac_enabled;
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value.get_bit(6);
+                if value.get_bit(7) {
+                    self.trigger();
+                }
+            }
+        }
+    }
+    fn next_tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+    fn output(&self) -> u8 {
+        match self.enabled {
+            true => {
+                DUTY_PATTERNS[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+            }
+            false => 0,
+        }
+    }
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    fn is_dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+    fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        match sweep.next_frequency() {
+            Some(frequency) if sweep.shift != 0 => {
+                sweep.shadow_frequency = frequency;
+                self.frequency = frequency;
+                // The new frequency is checked again for overflow
+                if sweep.next_frequency().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => (),
+            None => self.enabled = false,
+        }
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_step);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(writer);
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()?;
+        self.duty_step = reader.read_u8()?;
+        self.frequency = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SquareChannel;
+    use crate::apu::Channel;
+
+    #[test]
+    fn duty_test() {
+        let mut channel = SquareChannel::new(false);
+        channel.write_register(1, 0x80);
+        channel.write_register(2, 0xF0);
+        channel.write_register(3, 0xFF);
+        channel.write_register(4, 0x87);
+        // Period of 4 ticks, 50% duty
+        let mut outputs = vec![];
+        for _ in 0..8 {
+            outputs.push(channel.output());
+            for _ in 0..4 {
+                channel.next_tick();
+            }
+        }
+        assert_eq!(outputs, vec![15, 0, 0, 0, 0, 15, 15, 15]);
+    }
+    #[test]
+    fn sweep_overflow_test() {
+        let mut channel = SquareChannel::new(true);
+        // Period 1, addition, shift 1
+        channel.write_register(0, 0x11);
+        channel.write_register(2, 0xF0);
+        channel.write_register(3, 0x00);
+        channel.write_register(4, 0x85);
+        assert!(channel.is_enabled());
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x780);
+        // 0x780 + 0x3C0 overflows
+        assert!(!channel.is_enabled());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    apu::{Channel, LengthCounter},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{error_type::Errors, u8_traits::Bit},
+};
+
+/// Channel playing the 32 4-bit samples of the wave ram (0xFF30-0xFF3F)
+pub struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    // 0: mute, 1: 100%, 2: 50%, 3: 25%
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+    length: LengthCounter,
+    pub wave_ram: [u8; 16],
+}
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0u8; 16],
+        }
+    }
+    pub fn read_wave_ram(&self, address: u16) -> u8 {
+        self.wave_ram[(address - 0xFF30) as usize]
+    }
+    pub fn write_wave_ram(&mut self, address: u16, value: u8) {
+        self.wave_ram[(address - 0xFF30) as usize] = value;
+    }
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+}
+impl Default for WaveChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Channel for WaveChannel {
+    fn write_register(&mut self, index: u8, value: u8) {
+        match index {
+            0 => {
+                self.dac_enabled = value.get_bit(7);
+                self.enabled &= self.dac_enabled;
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value.get_bit(6);
+                if value.get_bit(7) {
+                    self.trigger();
+                }
+            }
+        }
+    }
+    fn next_tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            // High nibble first
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            self.sample = match self.position % 2 {
+                0 => byte >> 4,
+                _ => byte & 0x0F,
+            };
+        }
+    }
+    fn output(&self) -> u8 {
+        match (self.enabled, self.volume_code) {
+            (false, _) | (true, 0) => 0,
+            (true, code) => self.sample >> (code - 1),
+        }
+    }
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    fn is_dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.position);
+        writer.write_u8(self.sample);
+        self.length.save_state(writer);
+        writer.write_bytes(&self.wave_ram);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.volume_code = reader.read_u8()?;
+        self.frequency = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.position = reader.read_u8()?;
+        self.sample = reader.read_u8()?;
+        self.length.load_state(reader)?;
+        reader.read_bytes_into(&mut self.wave_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WaveChannel;
+    use crate::apu::Channel;
+
+    #[test]
+    fn wave_ram_test() {
+        let mut channel = WaveChannel::new();
+        channel.write_wave_ram(0xFF30, 0x12);
+        channel.write_wave_ram(0xFF31, 0x34);
+        channel.write_register(0, 0x80);
+        // 50% volume
+        channel.write_register(2, 0x40);
+        channel.write_register(3, 0xFF);
+        channel.write_register(4, 0x87);
+        // Period of 2 ticks, the first sample played is the second one
+        let mut outputs = vec![];
+        for _ in 0..3 {
+            for _ in 0..2 {
+                channel.next_tick();
+            }
+            outputs.push(channel.output());
+        }
+        assert_eq!(outputs, vec![1, 1, 2]);
+    }
+}
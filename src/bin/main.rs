@@ -1,70 +1,206 @@
 use game_boyish::{
-    bus::Bus,
-    cpu::CPU,
-    emulator::{Emulator, EmulatorState},
-    io_handler::IOHandler,
-    ppu::PPU,
-    quartz::Quartz,
-    timer_reg::TimerReg,
-    util::{
-        cartridge_util::{check_checksum, load, print_header},
-        extract_opcode::load_json,
-    },
-    windows::game_window::GameWindow,
+    config::{Config, DEFAULT_CONFIG_FILE},
+    emulator::Emulator,
+    frontend::WindowFrontend,
+    model::GameBoyModel,
+    util::cartridge_util::load_patched,
 };
-use std::{cell::RefCell, rc::Rc};
+use std::{env, path::Path, process::ExitCode, str::FromStr};
 
-fn main() {
-    println!("Welcome to GameBoyish the wanna be gameboy emulator!");
-    // let tetris_game = load("roms/Tetris (JUE) (V1.1) [!].gb").unwrap().0;
-    let mario_game = load("roms/Super Mario Land (JUE) (V1.1) [!].gb").unwrap().0;
-    let pokemon_game = load("roms/Pokemon Red.gb").unwrap().0;
+const USAGE: &str = "Usage: game_boyish [OPTIONS] <ROM>
 
-    let test_rom = load("/home/anon/Documents/Code/GameBoyish/roms/cpu_instrs/06-ld r,r.gb")
-        .unwrap()
-        .0;
-    let link_game = load("roms/Legend of Zelda, The - Link's Awakening (U) (V1.2) [!].gb")
-        .unwrap()
-        .0;
-    // let check1 = check_checksum(&tetris_game);
-    let check2 = check_checksum(&mario_game);
-    let check3 = check_checksum(&pokemon_game);
-    print_header(&test_rom);
-    // print_header(&mario_game);
-    // print_header(&pokemon_game);
-    // print_header(&link_game);
-    println!("check: {}", check2);
-    println!("check: {}", check3);
-    // let cpu:CPU = CPU::new();
-    let bus = Rc::new(RefCell::new(Bus::new()));
-    let mut doc_emu = Emulator {
-        cpu: CPU::new_doctor(Rc::clone(&bus)),
-        ppu: PPU::new(Rc::clone(&bus)),
-        quartz: Quartz::new(),
-        timer: TimerReg::new(Rc::clone(&bus)),
-        io_handler: IOHandler::new(Rc::clone(&bus)),
-        bus,
-        state: EmulatorState::Running,
-        cycles: 0,
-        screen: GameWindow::new(144 * 2, 160 * 2),
-        debug_screen: GameWindow::new(144 * 2, 160 * 2),
-    };
-    let bus = Rc::new(RefCell::new(Bus::new()));
-    let mut emu = Emulator {
-        cpu: CPU::new(Rc::clone(&bus)),
-        ppu: PPU::new(Rc::clone(&bus)),
-        quartz: Quartz::new(),
-        timer: TimerReg::new(Rc::clone(&bus)),
-        io_handler: IOHandler::new(Rc::clone(&bus)),
-        bus,
-        state: EmulatorState::Running,
-        cycles: 0,
-        screen: GameWindow::new(144 * 2, 160 * 2),
-        debug_screen: GameWindow::new(144 * 2, 160 * 2),
+Options:
+  --boot-rom FILE  Run the boot rom instead of skipping it
+  --model MODEL    Hardware to emulate: dmg0, dmg, mgb, sgb or cgb
+  --headless       Run without window nor input, as fast as possible
+  --frames N       Stop after N frames
+  --trace FILE     Write the cpu state after each instruction to FILE
+  --scale N        Window size as a multiple of the 160x144 screen
+  --speed SPEED    Multiple of the console speed
+  --config FILE    Config file to use instead of game_boyish.json
+  --info           Print the cartridge header and exit
+  -h, --help       Print this help";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    rom: Option<String>,
+    boot_rom: Option<String>,
+    model: Option<GameBoyModel>,
+    headless: bool,
+    frames: Option<u64>,
+    trace: Option<String>,
+    scale: Option<usize>,
+    speed: Option<f32>,
+    config: Option<String>,
+    info: bool,
+    help: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        // Values can be given as "--scale 3" or "--scale=3"
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} expects a value", name))
+        };
+        match name {
+            "--boot-rom" => parsed.boot_rom = Some(value()?),
+            "--model" => parsed.model = Some(value()?.parse()?),
+            "--headless" => parsed.headless = true,
+            "--frames" => parsed.frames = Some(parse_value(name, &value()?)?),
+            "--trace" => parsed.trace = Some(value()?),
+            "--scale" => parsed.scale = Some(parse_value(name, &value()?)?),
+            "--speed" => parsed.speed = Some(parse_value(name, &value()?)?),
+            "--config" => parsed.config = Some(value()?),
+            "--info" => parsed.info = true,
+            "-h" | "--help" => parsed.help = true,
+            _ if name.starts_with('-') => return Err(format!("unknown option {}", name)),
+            _ if parsed.rom.is_none() => parsed.rom = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if parsed.scale == Some(0) {
+        return Err("--scale must be at least 1".to_string());
+    }
+    if parsed.speed.is_some_and(|speed| speed <= 0.0) {
+        return Err("--speed must be positive".to_string());
+    }
+    Ok(parsed)
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, name))
+}
+
+// The command line takes precedence over the config file, per-game overrides included
+fn apply_args(config: &mut Config, args: &Args) {
+    if let Some(rom) = &args.rom {
+        config.rom = Some(rom.clone());
+    }
+    if let Some(model) = args.model {
+        config.model = model;
+        config.games.values_mut().for_each(|game| game.model = None);
+    }
+    if let Some(boot_rom) = &args.boot_rom {
+        for model in GameBoyModel::ALL {
+            config.boot_roms.insert(model, boot_rom.clone());
+        }
+    }
+    if let Some(trace) = &args.trace {
+        config.log_file = Some(trace.clone());
+    }
+    if let Some(scale) = args.scale {
+        config.video.scale = scale;
+        for video in config
+            .games
+            .values_mut()
+            .filter_map(|game| game.video.as_mut())
+        {
+            video.scale = scale;
+        }
+    }
+    if let Some(speed) = args.speed {
+        config.speed = speed;
+        config.games.values_mut().for_each(|game| game.speed = None);
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let config_path = args.config.as_deref().unwrap_or(DEFAULT_CONFIG_FILE);
+    let mut config = Config::load_or_default(Path::new(config_path))
+        .map_err(|e| format!("{}: {}", config_path, e))?;
+    apply_args(&mut config, &args);
+    let rom = config.rom.clone().ok_or("no rom given")?;
+    if !Path::new(&rom).is_file() {
+        return Err(format!("{}: no such file", rom));
+    }
+    if args.info {
+        let header = load_patched(&rom)
+            .and_then(|data| data.get_header())
+            .map_err(|e| format!("{}: {}", rom, e))?;
+        println!("{}", header);
+        return Ok(());
+    }
+
+    let mut emulator = Emulator::new();
+    if args.headless {
+        emulator.apply_config(&config).map_err(|e| e.to_string())?;
+        while args
+            .frames
+            .is_none_or(|frames| emulator.frame_count() < frames)
+        {
+            emulator.next_tick();
+        }
+        emulator.shutdown();
+        return Ok(());
+    }
+    let mut frontend = WindowFrontend::new(emulator);
+    frontend.frame_limit = args.frames;
+    frontend.init(&config).map_err(|e| e.to_string())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
     };
-    // change to doc_emu for use with doctor
-    let mut emu = doc_emu;
-    emu.init();
-    let opcodes_pre = load_json("opcodes_pre.json");
-    let opcodes_nopre = load_json("opcodes_nopre.json");
+    if args.help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, Args};
+    use game_boyish::model::GameBoyModel;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_args_test() {
+        let args = parse(&[
+            "--model",
+            "CGB",
+            "--frames=60",
+            "--headless",
+            "game.gb",
+            "--scale",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(args.rom.as_deref(), Some("game.gb"));
+        assert_eq!(args.model, Some(GameBoyModel::CGB));
+        assert_eq!(args.frames, Some(60));
+        assert_eq!(args.scale, Some(3));
+        assert!(args.headless);
+        assert!(!args.info);
+        assert!(parse(&["--frames"]).is_err());
+        assert!(parse(&["--frames", "ten"]).is_err());
+        assert!(parse(&["--model", "gba"]).is_err());
+        assert!(parse(&["--speed", "0"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+        assert!(parse(&["a.gb", "b.gb"]).is_err());
+    }
 }
@@ -1,14 +1,20 @@
 use crate::{
+    apu::APU,
+    cartridge::Cartridge,
+    joypad::{Button, Joypad},
+    model::GameBoyModel,
     ppu::PPUModes,
+    save_state::{SaveState, StateReader, StateWriter},
+    timer_reg::{timer_input, TimaState, TIMA_RELOAD_DELAY},
     util::{
-        cartridge_util::load,
+        cartridge_util::{load, load_patched},
         error_type::Errors,
         u8_traits::{Bit, NibblesU16},
     },
 };
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, path::Path, rc::Rc};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum InteruptType {
     VBlank,
     LCD,
@@ -268,10 +274,41 @@ impl OAMSprite {
 
 pub struct Bus {
     pub data: [u8; 0x1_0000],
+    // When a cartridge is inserted it answers 0x0000-0x7FFF and 0xA000-0xBFFF
+    pub cartridge: Option<Cartridge>,
+    pub apu: APU,
+    pub joypad: Joypad,
+    // Shadow the start of the rom until 0xFF50 is written to
+    boot_rom: Option<Vec<u8>>,
     pub timer_div_intern: u16,
-    pub timer_tima_intern: u16,
+    pub timer_tima_state: TimaState,
     vram_lock: bool,
     oam_lock: bool,
+    // OAM DMA started by writing to 0xFF46
+    dma_active: bool,
+    dma_source: u16,
+    dma_cycles: u16,
+}
+// Bits of the IO registers that are not used and always read as 1
+fn io_unused_bits(address: u16) -> u8 {
+    match address {
+        0xFF00 => 0xC0,
+        0xFF02 => 0x7E,
+        0xFF07 => 0xF8,
+        0xFF0F => 0xE0,
+        0xFF10 => 0x80,
+        // Sound length and frequency are write only
+        0xFF11 | 0xFF16 => 0x3F,
+        0xFF13 | 0xFF18 | 0xFF1B | 0xFF1D | 0xFF20 => 0xFF,
+        0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => 0xBF,
+        0xFF1A => 0x7F,
+        0xFF1C => 0x9F,
+        0xFF26 => 0x70,
+        0xFF41 => 0x80,
+        0xFF03 | 0xFF08..=0xFF0E | 0xFF15 | 0xFF1F | 0xFF27..=0xFF2F => 0xFF,
+        0xFF4C..=0xFF7F => 0xFF,
+        _ => 0x00,
+    }
 }
 impl std::fmt::Display for Bus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -306,31 +343,149 @@ impl Bus {
     pub fn new() -> Bus {
         Bus {
             data: [0x00; 0x1_0000],
+            cartridge: None,
+            apu: APU::new(),
+            joypad: Joypad::new(),
+            boot_rom: None,
             vram_lock: false,
             oam_lock: false,
             timer_div_intern: 0,
-            timer_tima_intern: 0,
+            timer_tima_state: TimaState::Counting,
+            dma_active: false,
+            dma_source: 0,
+            dma_cycles: 0,
+        }
+    }
+    // Tick the components plugged on the bus
+    pub fn next_tick(&mut self) {
+        if self.dma_active {
+            self.dma_tick();
+        }
+        self.apu.next_tick(self.timer_div_intern);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.next_tick();
         }
     }
+    pub fn is_dma_active(&self) -> bool {
+        self.dma_active
+    }
+    fn start_dma(&mut self, value: u8) {
+        self.dma_active = true;
+        self.dma_source = (value as u16) << 8;
+        self.dma_cycles = 0;
+    }
+    // One byte is copied to OAM every 4 clock ticks, 160 bytes in 160 M-cycles
+    fn dma_tick(&mut self) {
+        self.dma_cycles += 1;
+        if !self.dma_cycles.is_multiple_of(4) {
+            return;
+        }
+        let index = self.dma_cycles / 4 - 1;
+        self.data[0xFE00 + index as usize] = self.read_byte(self.dma_source + index);
+        if index == 0x9F {
+            self.dma_active = false;
+        }
+    }
+    /// Press or release a button, requesting the joypad interupt if the game sees the press
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.write_bit(0xFF0F, 4, true);
+        }
+    }
+    pub fn timer_input(&self) -> bool {
+        timer_input(self.timer_div_intern, self.read_byte(0xFF07))
+    }
+    /// Increment TIMA, on overflow it stays at 0 until reloaded from TMA
+    pub fn increment_tima(&mut self) {
+        let (tima, overflow) = self.read_byte(0xFF05).overflowing_add(1);
+        self.write_byte(0xFF05, tima);
+        if overflow {
+            self.timer_tima_state = TimaState::Overflowed(TIMA_RELOAD_DELAY);
+        }
+    }
+    pub fn take_rumble_event(&mut self) -> Option<bool> {
+        self.cartridge.as_mut()?.take_rumble_event()
+    }
     pub fn init(&mut self) {
         self.load_boot_rom().unwrap();
     }
     pub fn load_boot_rom(&mut self) -> Result<(), Errors> {
-        self.load_file("boot_roms/dmg_boot.bin", 0x0000)
+        self.load_boot_rom_file("boot_roms/dmg_boot.bin")
     }
-    // TODO: make it work for more complexe cartridge
-    pub fn load_cartridge(&mut self, path: &str) -> Result<(), Errors> {
-        self.load_file(path, 0x0000)
+    pub fn load_boot_rom_file(&mut self, path: &str) -> Result<(), Errors> {
+        self.set_boot_rom(load(path)?.0);
+        Ok(())
+    }
+    /// Map the boot rom over the cartridge, 256 bytes for DMG and 2304 bytes for CGB
+    /// (the CGB boot rom leaves the cartridge header at 0x0100-0x01FF visible)
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
     }
-    fn load_file(&mut self, path: &str, address: u16) -> Result<(), Errors> {
-        let data = load(path)?;
-        self.write_slice(address, data.0.as_slice());
+    /// Set the IO registers and timer as the boot rom of the model leaves them
+    pub fn set_post_boot_state(&mut self, model: GameBoyModel) {
+        self.boot_rom = None;
+        for (address, value) in model.post_boot_io_registers() {
+            self.write_byte(address, value);
+        }
+        self.write_byte(0xFFFF, 0x00);
+        self.timer_div_intern = model.post_boot_div_counter();
+        self.write_byte(0xFF04, self.timer_div_intern.high_8nibble());
+    }
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+    fn read_boot_rom(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0100..=0x01FF => None,
+            _ => self.boot_rom.as_ref()?.get(address as usize).copied(),
+        }
+    }
+    pub fn load_cartridge(&mut self, path: &str) -> Result<(), Errors> {
+        let save_directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        self.load_cartridge_with_save_directory(path, save_directory)
+    }
+    /// The battery save is kept in the directory under the name of the rom
+    pub fn load_cartridge_with_save_directory(
+        &mut self,
+        path: &str,
+        save_directory: &Path,
+    ) -> Result<(), Errors> {
+        let mut cartridge = Cartridge::new(load_patched(path)?)?;
+        if cartridge.header.mbc_type.has_battery() {
+            let save_name = Path::new(path).with_extension("sav");
+            let save_path = save_directory.join(save_name.file_name().unwrap_or_default());
+            cartridge.set_save_file(&save_path)?;
+        }
+        self.cartridge = Some(cartridge);
         Ok(())
     }
+    /// Write the battery backed ram of the cartridge to its save file
+    pub fn save_cartridge(&self) -> Result<(), Errors> {
+        match &self.cartridge {
+            Some(cartridge) => cartridge.save(),
+            None => Ok(()),
+        }
+    }
     pub fn read_a8(&self, offset: u8) -> u8 {
         return self.read_byte_as_cpu(0xFF00 + offset as u16);
     }
     pub fn read_byte(&self, address: u16) -> u8 {
+        if let Some(value) = self.read_boot_rom(address) {
+            return value;
+        }
+        if let Some(cartridge) = &self.cartridge {
+            match address {
+                0x0000..=0x7FFF => return cartridge.read_rom(address),
+                0xA000..=0xBFFF => return cartridge.read_ram(address),
+                _ => (),
+            }
+        }
+        if address == 0xFF00 {
+            return self.joypad.read();
+        }
+        if (0xFF10..=0xFF3F).contains(&address) {
+            return self.apu.read_register(address);
+        }
         let add = address as usize;
         return self.data[add];
     }
@@ -343,13 +498,27 @@ impl Bus {
         //     return 0x90;
         // }
 
+        // During DMA the cpu only sees HRAM (and IO registers which are on their own bus)
+        if self.dma_active && address < 0xFF00 {
+            return 0xFF;
+        }
         if self.vram_lock && (0x8000..=0x9FFF).contains(&address) {
             return 0x90;
         }
         if self.oam_lock && (0xFE00..=0xFE9F).contains(&address) {
             return 0x90;
         }
-        return self.read_byte(address);
+        match address {
+            // Echo of the work ram
+            0xE000..=0xFDFF => self.read_byte(address - 0x2000),
+            // Unusable area, read as 0xFF while the oam is blocked otherwise as 0x00 on DMG
+            0xFEA0..=0xFEFF => match self.oam_lock {
+                true => 0xFF,
+                false => 0x00,
+            },
+            0xFF00..=0xFF7F => self.read_byte(address) | io_unused_bits(address),
+            _ => self.read_byte(address),
+        }
     }
     pub fn read_2_bytes_from_little_endian_address(&self, address: u16) -> u8 {
         let high = address.high_8nibble();
@@ -411,9 +580,21 @@ impl Bus {
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        let range = 0x0000u16..0x0099;
-        if range.contains(&address) {
-            println!("Writing begining: address{}", address);
+        if let Some(cartridge) = &mut self.cartridge {
+            match address {
+                0x0000..=0x7FFF => return cartridge.write_rom(address, value),
+                0xA000..=0xBFFF => return cartridge.write_ram(address, value),
+                _ => (),
+            }
+        }
+        if address == 0xFF00 {
+            if self.joypad.write(value) {
+                self.write_bit(0xFF0F, 4, true);
+            }
+            return;
+        }
+        if (0xFF10..=0xFF3F).contains(&address) {
+            return self.apu.write_register(address, value);
         }
         self.data[address as usize] = value;
     }
@@ -424,8 +605,37 @@ impl Bus {
 
         // Reset counter if accessing 0xFF04 div timer
         if address == 0xFF04 {
+            let old_input = self.timer_input();
             self.write_byte(0xFF04, 0);
             self.timer_div_intern = 0;
+            if old_input {
+                self.increment_tima();
+            }
+            return;
+        }
+        match (address, self.timer_tima_state) {
+            // Writing TIMA while it overflowed cancels the reload
+            (0xFF05, TimaState::Overflowed(_)) => self.timer_tima_state = TimaState::Counting,
+            (0xFF05, TimaState::Reloaded(_)) => return,
+            (0xFF06, TimaState::Reloaded(_)) => self.write_byte(0xFF05, value),
+            (0xFF07, _) => {
+                let old_input = self.timer_input();
+                self.write_byte(0xFF07, value);
+                if old_input && !self.timer_input() {
+                    self.increment_tima();
+                }
+                return;
+            }
+            _ => (),
+        }
+        if address == 0xFF46 {
+            self.start_dma(value);
+        }
+        // Unmapping the boot rom can't be undone
+        if address == 0xFF50 && value != 0 {
+            self.boot_rom = None;
+        }
+        if self.dma_active && address < 0xFF00 {
             return;
         }
         if self.vram_lock && (0x8000..=0x9FFF).contains(&address) {
@@ -434,7 +644,13 @@ impl Bus {
         if self.oam_lock && (0xFE00..=0xFE9F).contains(&address) {
             return;
         }
-        self.write_byte(address, value)
+        match address {
+            // Without a cartridge mbc the rom can't be written to
+            0x0000..=0x7FFF if self.cartridge.is_none() => (),
+            0xE000..=0xFDFF => self.write_byte(address - 0x2000, value),
+            0xFEA0..=0xFEFF => (),
+            _ => self.write_byte(address, value),
+        }
     }
     //TODO: should the write happen in little endian?
     //I feel like it should be the same as the load method
@@ -481,16 +697,81 @@ impl Bus {
     }
 }
 
+impl SaveState for Bus {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save_state(writer);
+        }
+        self.apu.save_state(writer);
+        self.joypad.save_state(writer);
+        writer.write_bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = &self.boot_rom {
+            writer.write_bytes(boot_rom);
+        }
+        writer.write_u16(self.timer_div_intern);
+        let (tima_state, ticks) = match self.timer_tima_state {
+            TimaState::Counting => (0, 0),
+            TimaState::Overflowed(ticks) => (1, ticks),
+            TimaState::Reloaded(ticks) => (2, ticks),
+        };
+        writer.write_u8(tima_state);
+        writer.write_u8(ticks);
+        writer.write_bool(self.vram_lock);
+        writer.write_bool(self.oam_lock);
+        writer.write_bool(self.dma_active);
+        writer.write_u16(self.dma_source);
+        writer.write_u16(self.dma_cycles);
+    }
+    // The cartridge must be the one the state was saved with
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        reader.read_bytes_into(&mut self.data)?;
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.load_state(reader)?;
+        }
+        self.apu.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.boot_rom = match reader.read_bool()? {
+            true => Some(reader.read_bytes()?.to_vec()),
+            false => None,
+        };
+        self.timer_div_intern = reader.read_u16()?;
+        self.timer_tima_state = match (reader.read_u8()?, reader.read_u8()?) {
+            (0, _) => TimaState::Counting,
+            (1, ticks) => TimaState::Overflowed(ticks),
+            (2, ticks) => TimaState::Reloaded(ticks),
+            (state, _) => {
+                return Err(Errors::InvalidSaveState(format!(
+                    "invalid timer state {}",
+                    state
+                )))
+            }
+        };
+        self.vram_lock = reader.read_bool()?;
+        self.oam_lock = reader.read_bool()?;
+        self.dma_active = reader.read_bool()?;
+        self.dma_source = reader.read_u16()?;
+        self.dma_cycles = reader.read_u16()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
 
-    use crate::bus::{Bus, InteruptReg, InteruptType};
+    use crate::{
+        bus::{Bus, InteruptReg, InteruptType},
+        cartridge::Cartridge,
+        joypad::Button,
+        model::GameBoyModel,
+        util::cartridge_util::CartridgeData,
+    };
     #[test]
     fn test_read() {
         let mut bus = Bus::new();
         bus.data[0x0003] = 0xFF;
-        bus.data[0xF003] = 0xFC;
+        bus.data[0xD003] = 0xFC;
         bus.data[0xFFFF] = 0xFC;
         assert_eq!(bus.read_byte_as_cpu(0x0003), 0xFF);
         assert_eq!(bus.read_byte_as_cpu(0x0001), 0x00);
@@ -502,9 +783,32 @@ mod tests {
     #[test]
     fn test_write() {
         let mut bus = Bus::new();
-        assert_ne!(bus.read_byte_as_cpu(0x0010), 0x12);
+        assert_ne!(bus.read_byte_as_cpu(0xC010), 0x12);
+        bus.write_byte_as_cpu(0xC010, 0x12);
+        assert_eq!(bus.read_byte_as_cpu(0xC010), 0x12);
+    }
+    #[test]
+    fn memory_map_test() {
+        let mut bus = Bus::new();
+        // Rom is read only
+        bus.data[0x0010] = 0x34;
         bus.write_byte_as_cpu(0x0010, 0x12);
-        assert_eq!(bus.read_byte_as_cpu(0x0010), 0x12);
+        assert_eq!(bus.read_byte_as_cpu(0x0010), 0x34);
+        // Echo ram
+        bus.write_byte_as_cpu(0xC123, 0x56);
+        assert_eq!(bus.read_byte_as_cpu(0xE123), 0x56);
+        bus.write_byte_as_cpu(0xFDFF, 0x78);
+        assert_eq!(bus.read_byte(0xDDFF), 0x78);
+        // Unusable area
+        bus.write_byte_as_cpu(0xFEA0, 0x12);
+        assert_eq!(bus.read_byte_as_cpu(0xFEA0), 0x00);
+        // Unused bits of the io registers
+        assert_eq!(bus.read_byte_as_cpu(0xFF41), 0x80);
+        assert_eq!(bus.read_byte_as_cpu(0xFF0F), 0xE0);
+        assert_eq!(bus.read_byte_as_cpu(0xFF4C), 0xFF);
+        bus.write_byte_as_cpu(0xFF07, 0x05);
+        assert_eq!(bus.read_byte_as_cpu(0xFF07), 0xFD);
+        assert_eq!(bus.read_byte_as_cpu(0xFF80), 0x00);
     }
     #[test]
     fn read_slice() {
@@ -538,12 +842,28 @@ mod tests {
     fn load_boot_loader() {
         let mut bus = Bus::new();
         let _ = bus.load_boot_rom();
-        assert_eq!(
-            bus.read_bytes_range(0x0000, 5),
-            &[0x31, 0xfe, 0xff, 0xaf, 0x21]
-        );
+        assert_eq!(bus.read_byte_as_cpu(0x0000), 0x31);
+        assert_eq!(bus.read_byte_as_cpu(0x0004), 0x21);
         assert_eq!(bus.read_byte_as_cpu(0x00FF), 0x50);
     }
+    #[test]
+    fn boot_rom_overlay_test() {
+        let mut bus = Bus::new();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0000] = 0xC3;
+        rom[0x0100] = 0xAB;
+        bus.cartridge = Some(Cartridge::new(CartridgeData(rom)).unwrap());
+        bus.set_boot_rom(vec![0x31; 0x100]);
+        assert_eq!(bus.read_byte_as_cpu(0x0000), 0x31);
+        assert_eq!(bus.read_byte_as_cpu(0x00FF), 0x31);
+        assert_eq!(bus.read_byte_as_cpu(0x0100), 0xAB);
+        bus.write_byte_as_cpu(0xFF50, 0x00);
+        assert!(bus.is_boot_rom_mapped());
+        bus.write_byte_as_cpu(0xFF50, 0x01);
+        assert!(!bus.is_boot_rom_mapped());
+        assert_eq!(bus.read_byte_as_cpu(0x0000), 0xC3);
+        assert_eq!(bus.read_byte_as_cpu(0xFF50), 0xFF);
+    }
     // #[test]
     // fn get_tile_x_line_2bytes_test() {
     //     let mut bus = Bus::new();
@@ -551,6 +871,24 @@ mod tests {
     //     assert_eq!(bus.get_tile_x_line_2bytes(0x1111, 2), (0x00, 0x33));
     // }
     #[test]
+    fn joypad_test() {
+        let mut bus = Bus::new();
+        bus.write_byte_as_cpu(0xFF00, 0x10);
+        bus.set_button(Button::A, true);
+        assert_eq!(bus.read_byte_as_cpu(0xFF00), 0xDE);
+        assert_eq!(bus.read_byte_as_cpu(0xFF0F), 0xF0);
+    }
+    #[test]
+    fn post_boot_state_test() {
+        let mut bus = Bus::new();
+        bus.set_post_boot_state(GameBoyModel::DMG);
+        assert_eq!(bus.read_byte_as_cpu(0xFF40), 0x91);
+        assert_eq!(bus.read_byte_as_cpu(0xFF47), 0xFC);
+        assert_eq!(bus.read_byte_as_cpu(0xFF26), 0xF1);
+        assert_eq!(bus.read_byte_as_cpu(0xFF04), 0xAB);
+        assert_eq!(bus.timer_div_intern, 0xABCC);
+    }
+    #[test]
     fn get_a16_address_test() {
         let mut bus = Bus::new();
         bus.write_slice(0x0010, &[0x00, 0x10, 0x01]);
@@ -574,6 +912,42 @@ mod tests {
         assert_eq!(bus.read_byte_as_cpu(0x9F00), 0);
     }
     #[test]
+    fn cartridge_bank_switch_test() {
+        let mut rom = vec![0u8; 0x4000 * 4];
+        rom[0x0147] = 0x01;
+        rom[0x4000 * 3] = 0x33;
+        let mut bus = Bus::new();
+        bus.cartridge = Some(Cartridge::new(CartridgeData(rom)).unwrap());
+        bus.write_byte_as_cpu(0x2000, 0x03);
+        assert_eq!(bus.read_byte_as_cpu(0x4000), 0x33);
+        assert_eq!(bus.read_byte_as_cpu(0x0147), 0x01);
+        // Writing to rom doesn't change the rom
+        bus.write_byte_as_cpu(0x0147, 0x05);
+        assert_eq!(bus.read_byte_as_cpu(0x0147), 0x01);
+    }
+    #[test]
+    fn oam_dma_test() {
+        let mut bus = Bus::new();
+        let sprites: Vec<u8> = (0..0xA0).collect();
+        bus.write_slice(0xC100, &sprites);
+        bus.write_byte_as_cpu(0xFF80, 0x42);
+        bus.write_byte_as_cpu(0xFF46, 0xC1);
+        assert_eq!(bus.read_byte_as_cpu(0xFF46), 0xC1);
+        // Only HRAM is accessible during the transfer
+        assert!(bus.is_dma_active());
+        assert_eq!(bus.read_byte_as_cpu(0xC100), 0xFF);
+        assert_eq!(bus.read_byte_as_cpu(0xFF80), 0x42);
+        for _ in 0..160 * 4 - 1 {
+            bus.next_tick();
+        }
+        assert!(bus.is_dma_active());
+        assert_eq!(bus.read_byte(0xFE9F), 0x00);
+        bus.next_tick();
+        assert!(!bus.is_dma_active());
+        assert_eq!(bus.read_bytes_range(0xFE00, 0xA0), sprites.as_slice());
+        assert_eq!(bus.read_byte_as_cpu(0xC100), 0x00);
+    }
+    #[test]
     fn reset_flag_test() {
         let bus_rc = Rc::new(RefCell::new(Bus::new()));
         let mut interupt_reg = InteruptReg::new(Rc::clone(&bus_rc));
@@ -587,7 +961,7 @@ mod tests {
         {
             let bus = bus_rc.borrow_mut();
             println!("0xFF0F: {}", bus.read_byte_as_cpu(0xFF0F));
-            assert_eq!(bus.read_byte_as_cpu(0xFF0F), 0b0000_0000);
+            assert_eq!(bus.read_byte_as_cpu(0xFF0F), 0b1110_0000);
         }
     }
 }
@@ -1,22 +1,209 @@
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rom_only;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
 use crate::{
-    bus::Bus,
-    util::cartridge_util::{load, CartridgeData, MBCType, CARTRIDGE_TYPE},
+    cartridge::{mbc1::MBC1, mbc2::MBC2, mbc3::MBC3, mbc5::MBC5, rom_only::RomOnly},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{
+        cartridge_util::{CartridgeData, CartridgeHeader, MBCType},
+        error_type::Errors,
+    },
 };
-use std::{cell::RefCell, rc::Rc};
 
-struct Cartridge {
-    mbc_type: MBCType,
-    data: CartridgeData,
-    pub bus: Rc<RefCell<Bus>>,
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Memory bank controller living in the cartridge, it sees every cpu access
+/// to 0x0000-0x7FFF (rom and control registers) and 0xA000-0xBFFF (external ram),
+/// its save state only holds the registers, the ram is saved by the cartridge
+pub trait MBC: SaveState {
+    fn read_rom(&self, address: u16) -> u8;
+    /// Writes to the rom area never reach the rom, they set the mbc registers
+    fn write_rom(&mut self, address: u16, value: u8);
+    fn read_ram(&self, address: u16) -> u8;
+    fn write_ram(&mut self, address: u16, value: u8);
+    /// Whole external ram, as stored in .sav files
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    /// Cartridges without enable register always have their ram accessible
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+    /// Called every clock tick, for the mbc that have a clock
+    fn next_tick(&mut self) {}
+    /// New rumble motor state if it changed since the last call
+    fn take_rumble_event(&mut self) -> Option<bool> {
+        None
+    }
+    /// Clock state appended after the ram in .sav files
+    fn save_rtc(&self) -> Option<Vec<u8>> {
+        None
+    }
+    fn load_rtc(&mut self, _data: &[u8]) {}
+}
+
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    mbc: Box<dyn MBC>,
+    // File where the battery backed ram is persisted
+    save_path: Option<PathBuf>,
 }
 impl Cartridge {
-    fn new(bus: Rc<RefCell<Bus>>, file_path: &str) -> Cartridge {
-        let data = load(file_path).unwrap();
+    pub fn new(data: CartridgeData) -> Result<Cartridge, Errors> {
+        let header = data.get_header()?;
+        let ram_size = header.ram_size;
+        let mbc: Box<dyn MBC> = match header.mbc_type {
+            MBCType::ROM_ONLY | MBCType::ROM_RAM_1 | MBCType::ROM_RAM_BATTERY_1 => {
+                Box::new(RomOnly::new(data.0, ram_size))
+            }
+            MBCType::MBC1 | MBCType::MBC1_RAM | MBCType::MBC1_RAM_BATTERY => {
+                Box::new(MBC1::new(data.0, ram_size))
+            }
+            MBCType::MBC2 | MBCType::MBC2_BATTERY => Box::new(MBC2::new(data.0)),
+            MBCType::MBC3_TIMER_BATTERY | MBCType::MBC3_TIMER_RAM_BATTERY_2 => {
+                Box::new(MBC3::new(data.0, ram_size, true))
+            }
+            MBCType::MBC3 | MBCType::MBC3_RAM_2 | MBCType::MBC3_RAM_BATTERY_2 => {
+                Box::new(MBC3::new(data.0, ram_size, false))
+            }
+            MBCType::MBC5 | MBCType::MBC5_RAM | MBCType::MBC5_RAM_BATTERY => {
+                Box::new(MBC5::new(data.0, ram_size, false))
+            }
+            MBCType::MBC5_RUMBLE | MBCType::MBC5_RUMBLE_RAM | MBCType::MBC5_RUMBLE_RAM_BATTERY => {
+                Box::new(MBC5::new(data.0, ram_size, true))
+            }
+            mbc_type => return Err(Errors::UnsupportedMBC(mbc_type)),
+        };
+        Ok(Cartridge {
+            header,
+            mbc,
+            save_path: None,
+        })
+    }
+    /// Cartridge using a controller not described by the header
+    pub fn with_mbc(header: CartridgeHeader, mbc: Box<dyn MBC>) -> Cartridge {
         Cartridge {
-            mbc_type: data.get_mbc_type(),
-            data,
-            bus,
+            header,
+            mbc,
+            save_path: None,
         }
     }
-    // fn switch
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.mbc.read_rom(address)
+    }
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        let was_enabled = self.mbc.ram_enabled();
+        self.mbc.write_rom(address, value);
+        // Games disable the ram when they are done writing to it, good time to save
+        if was_enabled && !self.mbc.ram_enabled() {
+            if let Err(e) = self.save() {
+                eprintln!("Failed to write save file: {:?}", e);
+            }
+        }
+    }
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mbc.read_ram(address)
+    }
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        self.mbc.write_ram(address, value)
+    }
+    pub fn next_tick(&mut self) {
+        self.mbc.next_tick()
+    }
+    pub fn take_rumble_event(&mut self) -> Option<bool> {
+        self.mbc.take_rumble_event()
+    }
+    /// Persist the ram in the given file, loading it first if it already exists
+    pub fn set_save_file(&mut self, path: &Path) -> Result<(), Errors> {
+        if path.exists() {
+            self.load_save_data(&fs::read(path)?);
+        }
+        self.save_path = Some(path.to_path_buf());
+        Ok(())
+    }
+    /// Ram followed by the rtc trailer, same layout as the .sav of other emulators
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.mbc.ram().to_vec();
+        if let Some(rtc) = self.mbc.save_rtc() {
+            data.extend_from_slice(&rtc);
+        }
+        data
+    }
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram = self.mbc.ram_mut();
+        let ram_len = ram.len().min(data.len());
+        ram[..ram_len].copy_from_slice(&data[..ram_len]);
+        if data.len() > ram_len {
+            self.mbc.load_rtc(&data[ram_len..]);
+        }
+    }
+    /// Write the save file, does nothing for cartridges without battery
+    pub fn save(&self) -> Result<(), Errors> {
+        if let Some(path) = &self.save_path {
+            fs::write(path, self.save_data())?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of 16KiB banks in the rom, rounded up to a power of two so
+/// it can be used as a mask for the bank number
+pub fn rom_bank_count(rom: &[u8]) -> usize {
+    rom.len().div_ceil(ROM_BANK_SIZE).max(2).next_power_of_two()
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(self.mbc.ram());
+        self.mbc.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        reader.read_bytes_into(self.mbc.ram_mut())?;
+        self.mbc.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::Cartridge;
+    use crate::util::cartridge_util::CartridgeData;
+
+    // MBC1_RAM_BATTERY with 8KiB of ram
+    fn battery_cartridge() -> Cartridge {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        Cartridge::new(CartridgeData(rom)).unwrap()
+    }
+
+    #[test]
+    fn save_on_ram_disable_test() {
+        let path = env::temp_dir().join("game_boyish_save_on_ram_disable_test.sav");
+        let _ = fs::remove_file(&path);
+        let mut cartridge = battery_cartridge();
+        cartridge.set_save_file(&path).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA010, 0x42);
+        assert!(!path.exists());
+        cartridge.write_rom(0x0000, 0x00);
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(data[0x10], 0x42);
+
+        let mut loaded = battery_cartridge();
+        loaded.set_save_file(&path).unwrap();
+        loaded.write_rom(0x0000, 0x0A);
+        assert_eq!(loaded.read_ram(0xA010), 0x42);
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,174 @@
+use crate::{
+    cartridge::{rom_bank_count, MBC, RAM_BANK_SIZE, ROM_BANK_SIZE},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::error_type::Errors,
+};
+
+/// MBC1 up to 2MiB of rom (125 usable banks) and 32KiB of ram (4 banks)
+pub struct MBC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank_count: usize,
+    ram_enable: bool,
+    // 5 bits register 0x2000-0x3FFF, 0 is read as 1
+    rom_bank_low: u8,
+    // 2 bits register 0x4000-0x5FFF, ram bank or bit 5-6 of the rom bank
+    bank_high: u8,
+    // false = simple banking mode, true = advanced banking mode
+    banking_mode: bool,
+}
+impl MBC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> MBC1 {
+        MBC1 {
+            rom_bank_count: rom_bank_count(&rom),
+            rom,
+            ram: vec![0u8; ram_size],
+            ram_enable: false,
+            rom_bank_low: 1,
+            bank_high: 0,
+            banking_mode: false,
+        }
+    }
+    // Bank mapped at 0x0000-0x3FFF, only switchable in advanced banking mode
+    fn rom_bank_zero(&self) -> usize {
+        match self.banking_mode {
+            true => ((self.bank_high as usize) << 5) & (self.rom_bank_count - 1),
+            false => 0,
+        }
+    }
+    // Bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> usize {
+        (((self.bank_high as usize) << 5) | self.rom_bank_low as usize) & (self.rom_bank_count - 1)
+    }
+    fn ram_bank(&self) -> usize {
+        match self.banking_mode {
+            true => self.bank_high as usize,
+            false => 0,
+        }
+    }
+    fn ram_address(&self, address: u16) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+impl MBC for MBC1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => self.rom_bank_zero() * ROM_BANK_SIZE + address as usize,
+            _ => self.rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+        };
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank_low = match value & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x5FFF => self.bank_high = value & 0x03,
+            _ => self.banking_mode = value & 0x01 == 1,
+        }
+    }
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.ram_address(address) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.ram_address(address) {
+            self.ram[offset] = value;
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_enabled(&self) -> bool {
+        self.ram_enable
+    }
+}
+
+impl SaveState for MBC1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank_low);
+        writer.write_u8(self.bank_high);
+        writer.write_bool(self.banking_mode);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank_low = reader.read_u8()?;
+        self.bank_high = reader.read_u8()?;
+        self.banking_mode = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MBC1;
+    use crate::cartridge::{MBC, ROM_BANK_SIZE};
+
+    // Rom where the first byte of every bank is the bank number
+    fn numbered_rom(bank_count: usize) -> Vec<u8> {
+        let mut rom = vec![0u8; bank_count * ROM_BANK_SIZE];
+        for bank in 0..bank_count {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn rom_bank_switch_test() {
+        let mut mbc = MBC1::new(numbered_rom(64), 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+        // bank 0 is translated to bank 1
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        // Upper bits of the bank number
+        mbc.write_rom(0x2000, 0x02);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x22);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        // Advanced mode also switch the 0x0000-0x3FFF area
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+    }
+    #[test]
+    fn rom_bank_mask_test() {
+        let mut mbc = MBC1::new(numbered_rom(8), 0);
+        mbc.write_rom(0x2000, 0x09);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+    }
+    #[test]
+    fn ram_bank_test() {
+        let mut mbc = MBC1::new(numbered_rom(4), 0x8000);
+        // Ram disabled
+        mbc.write_ram(0xA000, 0x12);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x12);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+        // Simple banking mode always use ram bank 0
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_ram(0xA000, 0x34);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+}
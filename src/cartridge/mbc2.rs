@@ -0,0 +1,123 @@
+use crate::{
+    cartridge::{rom_bank_count, MBC, ROM_BANK_SIZE},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::error_type::Errors,
+};
+
+const MBC2_RAM_SIZE: usize = 512;
+
+/// MBC2 up to 256KiB of rom (16 banks) with 512x4 bits of ram built in the mbc
+pub struct MBC2 {
+    rom: Vec<u8>,
+    // Only the lower nibble of each byte is stored
+    ram: [u8; MBC2_RAM_SIZE],
+    rom_bank_count: usize,
+    ram_enable: bool,
+    rom_bank: u8,
+}
+impl MBC2 {
+    pub fn new(rom: Vec<u8>) -> MBC2 {
+        MBC2 {
+            rom_bank_count: rom_bank_count(&rom).min(16),
+            rom,
+            ram: [0u8; MBC2_RAM_SIZE],
+            ram_enable: false,
+            rom_bank: 1,
+        }
+    }
+}
+impl MBC for MBC2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => {
+                (self.rom_bank as usize & (self.rom_bank_count - 1)) * ROM_BANK_SIZE
+                    + (address - 0x4000) as usize
+            }
+        };
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+    fn write_rom(&mut self, address: u16, value: u8) {
+        if address > 0x3FFF {
+            return;
+        }
+        // Bit 8 of the address select between the two registers
+        match address & 0x0100 {
+            0 => self.ram_enable = value & 0x0F == 0x0A,
+            _ => {
+                self.rom_bank = match value & 0x0F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+        }
+    }
+    // The 512 bytes are echoed in the whole 0xA000-0xBFFF area
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.ram_enable {
+            true => self.ram[(address & 0x01FF) as usize] | 0xF0,
+            false => 0xFF,
+        }
+    }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enable {
+            self.ram[(address & 0x01FF) as usize] = value & 0x0F;
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_enabled(&self) -> bool {
+        self.ram_enable
+    }
+}
+
+impl SaveState for MBC2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MBC2;
+    use crate::cartridge::{MBC, ROM_BANK_SIZE};
+
+    #[test]
+    fn register_select_test() {
+        let mut rom = vec![0u8; 16 * ROM_BANK_SIZE];
+        rom[0x0F * ROM_BANK_SIZE] = 0x0F;
+        let mut mbc = MBC2::new(rom);
+        // Bit 8 clear, it's the ram enable register
+        mbc.write_rom(0x2000, 0x0F);
+        assert_eq!(mbc.read_rom(0x4000), 0x00);
+        mbc.write_rom(0x2100, 0x0F);
+        assert_eq!(mbc.read_rom(0x4000), 0x0F);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        mbc.write_rom(0x3E00, 0x0A);
+        assert_eq!(mbc.read_ram(0xA000), 0xF0);
+    }
+    #[test]
+    fn half_byte_ram_test() {
+        let mut mbc = MBC2::new(vec![0u8; 4 * ROM_BANK_SIZE]);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA001, 0xAB);
+        assert_eq!(mbc.read_ram(0xA001), 0xFB);
+        // Echo of the 512 half-bytes
+        assert_eq!(mbc.read_ram(0xA201), 0xFB);
+        assert_eq!(mbc.read_ram(0xBE01), 0xFB);
+        mbc.write_ram(0xBFFF, 0x03);
+        assert_eq!(mbc.read_ram(0xA1FF), 0xF3);
+    }
+}
@@ -0,0 +1,431 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    cartridge::{rom_bank_count, MBC, RAM_BANK_SIZE, ROM_BANK_SIZE},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::error_type::Errors,
+};
+
+// The clock is driven by the emulated cycles so it doesn't depend on the host
+const RTC_TICKS_PER_SECOND: u32 = 4_194_304;
+// Trailer appended to the save ram: current and latched registers as u32
+// followed by a u64 unix timestamp, older emulators use a u32 timestamp
+const RTC_SAVE_SIZE: usize = 48;
+const RTC_SAVE_SIZE_32BIT: usize = 44;
+
+/// Real time clock registers, selected with 0x08-0x0C in 0x4000-0x5FFF
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RealTimeClock {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    // 9 bits day counter
+    pub days: u16,
+    pub halt: bool,
+    pub day_carry: bool,
+    // cycles since the last second
+    cycles: u32,
+}
+impl RealTimeClock {
+    pub fn next_tick(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.cycles += 1;
+        if self.cycles >= RTC_TICKS_PER_SECOND {
+            self.cycles = 0;
+            self.tick_second();
+        }
+    }
+    // Counters only carry when reaching their normal limit, a value written
+    // above it just wraps around its bit width without carrying
+    pub fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+    /// Move the clock forward, used to catch up the time spent while the emulator was off
+    pub fn advance_seconds(&mut self, seconds: u64) {
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + self.days as u64 * 86400
+            + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+    pub fn read_register(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            0x0C => {
+                ((self.days >> 8) as u8 & 0x01)
+                    | ((self.halt as u8) << 6)
+                    | ((self.day_carry as u8) << 7)
+            }
+            _ => 0xFF,
+        }
+    }
+    pub fn write_register(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                // Writing the seconds resets the sub second counter
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.halt = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SaveState for RealTimeClock {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u16(self.days);
+        writer.write_bool(self.halt);
+        writer.write_bool(self.day_carry);
+        writer.write_u32(self.cycles);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.seconds = reader.read_u8()?;
+        self.minutes = reader.read_u8()?;
+        self.hours = reader.read_u8()?;
+        self.days = reader.read_u16()?;
+        self.halt = reader.read_bool()?;
+        self.day_carry = reader.read_bool()?;
+        self.cycles = reader.read_u32()?;
+        Ok(())
+    }
+}
+
+/// MBC3 up to 2MiB of rom (128 banks), 32KiB of ram (4 banks) and an
+/// optional real time clock
+pub struct MBC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank_count: usize,
+    // Enable both the ram and the rtc registers
+    ram_enable: bool,
+    rom_bank: u8,
+    // 0x00-0x03 select a ram bank, 0x08-0x0C a rtc register
+    ram_bank_rtc_select: u8,
+    rtc: Option<RealTimeClock>,
+    // Copy of the rtc taken at the last latch, it's what the cpu reads
+    rtc_latched: RealTimeClock,
+    last_latch_write: u8,
+}
+impl MBC3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> MBC3 {
+        MBC3 {
+            rom_bank_count: rom_bank_count(&rom),
+            rom,
+            ram: vec![0u8; ram_size],
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank_rtc_select: 0,
+            rtc: has_rtc.then(RealTimeClock::default),
+            rtc_latched: RealTimeClock::default(),
+            last_latch_write: 0xFF,
+        }
+    }
+    fn ram_address(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank_rtc_select as usize * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+impl MBC for MBC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => {
+                (self.rom_bank as usize & (self.rom_bank_count - 1)) * ROM_BANK_SIZE
+                    + (address - 0x4000) as usize
+            }
+        };
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = match value & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank_rtc_select = value & 0x0F,
+            _ => {
+                // Writing 0 then 1 copy the clock into the latched registers
+                if self.last_latch_write == 0x00 && value == 0x01 {
+                    if let Some(rtc) = &self.rtc {
+                        self.rtc_latched = rtc.clone();
+                    }
+                }
+                self.last_latch_write = value;
+            }
+        }
+    }
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xFF;
+        }
+        match self.ram_bank_rtc_select {
+            0x00..=0x03 => match self.ram_address(address) {
+                Some(offset) => self.ram[offset],
+                None => 0xFF,
+            },
+            register @ 0x08..=0x0C if self.rtc.is_some() => {
+                self.rtc_latched.read_register(register)
+            }
+            _ => 0xFF,
+        }
+    }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enable {
+            return;
+        }
+        match self.ram_bank_rtc_select {
+            0x00..=0x03 => {
+                if let Some(offset) = self.ram_address(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            register @ 0x08..=0x0C => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_register(register, value);
+                    self.rtc_latched.write_register(register, value);
+                }
+            }
+            _ => (),
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_enabled(&self) -> bool {
+        self.ram_enable
+    }
+    fn next_tick(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.next_tick();
+        }
+    }
+    fn save_rtc(&self) -> Option<Vec<u8>> {
+        let rtc = self.rtc.as_ref()?;
+        let mut data = Vec::with_capacity(RTC_SAVE_SIZE);
+        for clock in [rtc, &self.rtc_latched] {
+            for register in 0x08..=0x0C {
+                data.extend_from_slice(&(clock.read_register(register) as u32).to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&unix_timestamp().to_le_bytes());
+        Some(data)
+    }
+    fn load_rtc(&mut self, data: &[u8]) {
+        let Some(rtc) = &mut self.rtc else {
+            return;
+        };
+        let timestamp = match data.len() {
+            RTC_SAVE_SIZE.. => u64::from_le_bytes(data[40..48].try_into().unwrap()),
+            RTC_SAVE_SIZE_32BIT.. => u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64,
+            _ => return,
+        };
+        for (i, register) in (0x08..=0x0C).enumerate() {
+            rtc.write_register(register, data[i * 4]);
+            self.rtc_latched.write_register(register, data[20 + i * 4]);
+        }
+        if !rtc.halt {
+            rtc.advance_seconds(unix_timestamp().saturating_sub(timestamp));
+        }
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// The clock state is the emulated one, not caught up with the host time like in .sav files
+impl SaveState for MBC3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_bank_rtc_select);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+        self.rtc_latched.save_state(writer);
+        writer.write_u8(self.last_latch_write);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        self.ram_bank_rtc_select = reader.read_u8()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        self.rtc_latched.load_state(reader)?;
+        self.last_latch_write = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MBC3, RTC_TICKS_PER_SECOND};
+    use crate::cartridge::{MBC, ROM_BANK_SIZE};
+
+    fn enabled_mbc3() -> MBC3 {
+        let mut mbc = MBC3::new(vec![0u8; 128 * ROM_BANK_SIZE], 0x8000, true);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc
+    }
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    #[test]
+    fn rom_bank_test() {
+        let mut rom = vec![0u8; 128 * ROM_BANK_SIZE];
+        rom[0x7F * ROM_BANK_SIZE] = 0x7F;
+        rom[0x41 * ROM_BANK_SIZE] = 0x41;
+        let mut mbc = MBC3::new(rom, 0, false);
+        mbc.write_rom(0x2000, 0x7F);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+        mbc.write_rom(0x2000, 0x41);
+        assert_eq!(mbc.read_rom(0x4000), 0x41);
+    }
+    #[test]
+    fn ram_bank_test() {
+        let mut mbc = enabled_mbc3();
+        for bank in 0..4 {
+            mbc.write_rom(0x4000, bank);
+            mbc.write_ram(0xA000, bank + 0x10);
+        }
+        for bank in 0..4 {
+            mbc.write_rom(0x4000, bank);
+            assert_eq!(mbc.read_ram(0xA000), bank + 0x10);
+        }
+    }
+    #[test]
+    fn rtc_latch_test() {
+        let mut mbc = enabled_mbc3();
+        mbc.write_rom(0x4000, 0x08);
+        for _ in 0..RTC_TICKS_PER_SECOND * 3 {
+            mbc.next_tick();
+        }
+        // Not latched yet
+        assert_eq!(mbc.read_ram(0xA000), 0);
+        latch(&mut mbc);
+        assert_eq!(mbc.read_ram(0xA000), 3);
+        for _ in 0..RTC_TICKS_PER_SECOND {
+            mbc.next_tick();
+        }
+        // Writing 1 without the 0 first doesn't latch
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 3);
+        latch(&mut mbc);
+        assert_eq!(mbc.read_ram(0xA000), 4);
+    }
+    #[test]
+    fn rtc_save_test() {
+        let mut mbc = enabled_mbc3();
+        mbc.write_rom(0x4000, 0x0A);
+        mbc.write_ram(0xA000, 5);
+        mbc.write_rom(0x4000, 0x0C);
+        // Halted so the time spent between save and load is not added
+        mbc.write_ram(0xA000, 0x41);
+        let save = mbc.save_rtc().unwrap();
+        assert_eq!(save.len(), 48);
+        assert_eq!(save[8..12], [5, 0, 0, 0]);
+        assert_eq!(save[16..20], [0x41, 0, 0, 0]);
+
+        let mut loaded = enabled_mbc3();
+        loaded.load_rtc(&save);
+        assert_eq!(loaded.rtc, mbc.rtc);
+        loaded.write_rom(0x4000, 0x0C);
+        assert_eq!(loaded.read_ram(0xA000), 0x41);
+    }
+    #[test]
+    fn rtc_advance_test() {
+        let mut mbc = enabled_mbc3();
+        let rtc = mbc.rtc.as_mut().unwrap();
+        rtc.advance_seconds(86400 * 2 + 3600 * 5 + 61);
+        assert_eq!(
+            (rtc.days, rtc.hours, rtc.minutes, rtc.seconds),
+            (2, 5, 1, 1)
+        );
+        rtc.advance_seconds(86400 * 510);
+        assert_eq!(rtc.days, 0);
+        assert!(rtc.day_carry);
+    }
+    #[test]
+    fn rtc_carry_and_halt_test() {
+        let mut mbc = enabled_mbc3();
+        let registers = [(0x08, 59), (0x09, 59), (0x0A, 23), (0x0B, 0xFF), (0x0C, 0x01)];
+        for (register, value) in registers {
+            mbc.write_rom(0x4000, register);
+            mbc.write_ram(0xA000, value);
+        }
+        mbc.rtc.as_mut().unwrap().tick_second();
+        latch(&mut mbc);
+        for register in 0x08..=0x0B {
+            mbc.write_rom(0x4000, register);
+            assert_eq!(mbc.read_ram(0xA000), 0);
+        }
+        mbc.write_rom(0x4000, 0x0C);
+        assert_eq!(mbc.read_ram(0xA000), 0x80);
+        // Halted clock doesn't count
+        mbc.write_ram(0xA000, 0x40);
+        for _ in 0..RTC_TICKS_PER_SECOND {
+            mbc.next_tick();
+        }
+        latch(&mut mbc);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xA000), 0);
+    }
+}
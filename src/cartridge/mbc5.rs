@@ -0,0 +1,169 @@
+use crate::{
+    cartridge::{rom_bank_count, MBC, RAM_BANK_SIZE, ROM_BANK_SIZE},
+    save_state::{SaveState, StateReader, StateWriter},
+    util::error_type::Errors,
+};
+
+/// MBC5 up to 8MiB of rom (512 banks) and 128KiB of ram (16 banks).
+/// On rumble cartridges bit 3 of the ram bank register drives the motor.
+pub struct MBC5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank_count: usize,
+    ram_enable: bool,
+    // 9 bits, unlike the other mbc bank 0 can be mapped at 0x4000-0x7FFF
+    rom_bank: u16,
+    ram_bank: u8,
+    // None when the cartridge has no motor
+    rumble: Option<bool>,
+    // Motor state change not yet seen by the emulator
+    rumble_event: Option<bool>,
+}
+impl MBC5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> MBC5 {
+        MBC5 {
+            rom_bank_count: rom_bank_count(&rom),
+            rom,
+            ram: vec![0u8; ram_size],
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble: has_rumble.then_some(false),
+            rumble_event: None,
+        }
+    }
+    fn ram_address(&self, address: u16) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+impl MBC for MBC5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => {
+                (self.rom_bank as usize & (self.rom_bank_count - 1)) * ROM_BANK_SIZE
+                    + (address - 0x4000) as usize
+            }
+        };
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8)
+            }
+            0x4000..=0x5FFF => match &mut self.rumble {
+                Some(motor) => {
+                    self.ram_bank = value & 0x07;
+                    let on = value & 0x08 != 0;
+                    if *motor != on {
+                        *motor = on;
+                        self.rumble_event = Some(on);
+                    }
+                }
+                None => self.ram_bank = value & 0x0F,
+            },
+            _ => (),
+        }
+    }
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.ram_address(address) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.ram_address(address) {
+            self.ram[offset] = value;
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn ram_enabled(&self) -> bool {
+        self.ram_enable
+    }
+    fn take_rumble_event(&mut self) -> Option<bool> {
+        self.rumble_event.take()
+    }
+}
+
+impl SaveState for MBC5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.rumble.unwrap_or(false));
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        self.ram_bank = reader.read_u8()?;
+        let motor_on = reader.read_bool()?;
+        // The motor follows the restored state
+        if let Some(rumble) = &mut self.rumble {
+            if *rumble != motor_on {
+                *rumble = motor_on;
+                self.rumble_event = Some(motor_on);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MBC5;
+    use crate::cartridge::{MBC, ROM_BANK_SIZE};
+
+    #[test]
+    fn rom_bank_9bit_test() {
+        let mut rom = vec![0u8; 512 * ROM_BANK_SIZE];
+        rom[0x1A5 * ROM_BANK_SIZE] = 0xA5;
+        rom[1] = 0x01;
+        let mut mbc = MBC5::new(rom, 0, false);
+        mbc.write_rom(0x2000, 0xA5);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0xA5);
+        // Bank 0 is not translated to 1
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mbc.read_rom(0x4001), 0x01);
+    }
+    #[test]
+    fn ram_bank_test() {
+        let mut mbc = MBC5::new(vec![0u8; 4 * ROM_BANK_SIZE], 0x2_0000, false);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x0F);
+        mbc.write_ram(0xA000, 0x0F);
+        mbc.write_rom(0x4000, 0x07);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_rom(0x4000, 0x0F);
+        assert_eq!(mbc.read_ram(0xA000), 0x0F);
+    }
+    #[test]
+    fn rumble_test() {
+        let mut mbc = MBC5::new(vec![0u8; 4 * ROM_BANK_SIZE], 0x8000, true);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x09);
+        assert_eq!(mbc.take_rumble_event(), Some(true));
+        assert_eq!(mbc.take_rumble_event(), None);
+        // Bit 3 is not a bank bit on rumble cartridges
+        mbc.write_ram(0xA000, 0x42);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+        assert_eq!(mbc.take_rumble_event(), Some(false));
+        // Same state again doesn't create an event
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.take_rumble_event(), None);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{
+    cartridge::MBC,
+    save_state::{SaveState, StateReader, StateWriter},
+    util::error_type::Errors,
+};
+
+/// 32KiB cartridge without mbc, optionally with up to 8KiB of ram
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom,
+            ram: vec![0u8; ram_size],
+        }
+    }
+}
+impl MBC for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        *self.rom.get(address as usize).unwrap_or(&0xFF)
+    }
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+    fn read_ram(&self, address: u16) -> u8 {
+        *self
+            .ram
+            .get((address - 0xA000) as usize)
+            .unwrap_or(&0xFF)
+    }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.ram.get_mut((address - 0xA000) as usize) {
+            *byte = value;
+        }
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+// No register, the ram is saved by the cartridge
+impl SaveState for RomOnly {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), Errors> {
+        Ok(())
+    }
+}
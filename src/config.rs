@@ -0,0 +1,345 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    io_handler::{key_from_name, key_name, KeyMapping},
+    joypad::Button,
+    model::GameBoyModel,
+    util::{cartridge_util::CartridgeHeader, error_type::Errors},
+};
+
+pub const DEFAULT_CONFIG_FILE: &str = "game_boyish.json";
+
+/// Emulator settings read from a json file, missing fields take their default value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub rom: Option<String>,
+    pub model: GameBoyModel,
+    pub input: InputConfig,
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    pub rewind: RewindConfig,
+    // The boot is skipped for the models without a boot rom
+    pub boot_roms: HashMap<GameBoyModel, String>,
+    // Battery saves go next to the rom when not set
+    pub save_directory: Option<String>,
+    // Multiple of the console clock speed
+    pub speed: f32,
+    // Cpu state after each instruction, disabled when not set
+    pub log_file: Option<String>,
+    // Keyed by cartridge title or global checksum in hexadecimal ("1F3C")
+    pub games: HashMap<String, GameConfig>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            rom: None,
+            model: GameBoyModel::DMG,
+            input: InputConfig::default(),
+            video: VideoConfig::default(),
+            audio: AudioConfig::default(),
+            rewind: RewindConfig::default(),
+            boot_roms: HashMap::new(),
+            save_directory: None,
+            speed: 1.0,
+            log_file: None,
+            games: HashMap::new(),
+        }
+    }
+}
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Errors> {
+        let config: Config = serde_json::from_str(&fs::read_to_string(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Default config if the file doesn't exist
+    pub fn load_or_default(path: &Path) -> Result<Config, Errors> {
+        match path.exists() {
+            true => Config::load(path),
+            false => Ok(Config::default()),
+        }
+    }
+    pub fn save(&self, path: &Path) -> Result<(), Errors> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+    fn validate(&self) -> Result<(), Errors> {
+        let configs = std::iter::once((None, self.speed, &self.input, &self.video)).chain(
+            self.games.iter().map(|(game, game_config)| {
+                (
+                    Some(game),
+                    game_config.speed.unwrap_or(1.0),
+                    game_config.input.as_ref().unwrap_or(&self.input),
+                    game_config.video.as_ref().unwrap_or(&self.video),
+                )
+            }),
+        );
+        for (game, speed, input, video) in configs {
+            let context = game
+                .map(|game| format!(" for {}", game))
+                .unwrap_or_default();
+            if speed <= 0.0 {
+                return Err(Errors::InvalidConfig(format!(
+                    "speed{} must be positive",
+                    context
+                )));
+            }
+            if video.scale == 0 {
+                return Err(Errors::InvalidConfig(format!(
+                    "scale{} must be at least 1",
+                    context
+                )));
+            }
+            input.key_mapping()?;
+        }
+        self.rewind.memory_limit_bytes()?;
+        Ok(())
+    }
+    /// Config with the overrides of the game applied, looked up by title then global checksum
+    pub fn for_game(&self, header: &CartridgeHeader) -> Config {
+        let checksum = format!("{:04X}", header.global_checksum);
+        let game_config = self.games.get(&header.title).or_else(|| {
+            self.games
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(&checksum))
+                .map(|(_, game_config)| game_config)
+        });
+        let mut config = self.clone();
+        if let Some(game_config) = game_config {
+            config.model = game_config.model.unwrap_or(config.model);
+            config.speed = game_config.speed.unwrap_or(config.speed);
+            if let Some(input) = &game_config.input {
+                config.input = input.clone();
+            }
+            if let Some(video) = &game_config.video {
+                config.video = video.clone();
+            }
+            if let Some(audio) = &game_config.audio {
+                config.audio = audio.clone();
+            }
+        }
+        config
+    }
+}
+
+/// Settings replaced for one game, a section replaces the whole section of the global config
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    pub model: Option<GameBoyModel>,
+    pub input: Option<InputConfig>,
+    pub video: Option<VideoConfig>,
+    pub audio: Option<AudioConfig>,
+    pub speed: Option<f32>,
+}
+
+/// Keyboard key name (minifb variant name) to console button
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputConfig {
+    pub keys: HashMap<String, Button>,
+    // Held to rewind, it can't be bound to a button too
+    pub rewind_key: String,
+}
+impl Default for InputConfig {
+    fn default() -> Self {
+        let mapping = KeyMapping::default();
+        InputConfig {
+            keys: mapping
+                .bindings()
+                .map(|(key, button)| (key_name(key), button))
+                .collect(),
+            rewind_key: key_name(mapping.rewind_key()),
+        }
+    }
+}
+impl InputConfig {
+    pub fn key_mapping(&self) -> Result<KeyMapping, Errors> {
+        let parse_key = |name: &str| {
+            key_from_name(name)
+                .ok_or_else(|| Errors::InvalidConfig(format!("unknown key {}", name)))
+        };
+        let mut mapping = KeyMapping::empty();
+        mapping.set_rewind_key(parse_key(&self.rewind_key)?);
+        for (name, &button) in &self.keys {
+            let key = parse_key(name)?;
+            if key == mapping.rewind_key() {
+                return Err(Errors::InvalidConfig(format!(
+                    "{} is the rewind key, it can't be bound to {:?}",
+                    name, button
+                )));
+            }
+            mapping.bind(key, button);
+        }
+        Ok(mapping)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VideoConfig {
+    // Window size as a multiple of the 160x144 screen
+    pub scale: usize,
+    // 0xRRGGBB colors of the 4 shades, from lightest to darkest
+    pub palette: [u32; 4],
+}
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            scale: 2,
+            palette: [0xFFFFFF, 0xAFAFAF, 0x555555, 0x000000],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    // Wav file the sound output is written to, disabled when not set
+    pub capture_file: Option<String>,
+    // Also write each channel to its own file next to the capture
+    pub capture_channels: bool,
+}
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: 44_100,
+            capture_file: None,
+            capture_channels: false,
+        }
+    }
+}
+
+/// Snapshots the window frontend goes back through while the rewind key is held
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewindConfig {
+    // Frames between two snapshots, 0 disables rewinding
+    pub interval: u64,
+    // Memory the snapshots can use, in MiB
+    pub memory_limit: usize,
+}
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig {
+            interval: 1,
+            memory_limit: 64,
+        }
+    }
+}
+impl RewindConfig {
+    /// Memory limit in bytes, rejected when it doesn't fit in a usize
+    pub fn memory_limit_bytes(&self) -> Result<usize, Errors> {
+        self.memory_limit.checked_mul(1 << 20).ok_or_else(|| {
+            Errors::InvalidConfig(format!(
+                "rewind memory limit of {} MiB is too big",
+                self.memory_limit
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, fs};
+
+    use super::{Config, InputConfig, DEFAULT_CONFIG_FILE};
+    use crate::{
+        io_handler::KeyMapping,
+        joypad::Button,
+        model::GameBoyModel,
+        util::{
+            cartridge_util::{CartridgeData, CartridgeHeader},
+            error_type::Errors,
+        },
+    };
+
+    fn header(title: &str, global_checksum: u16) -> CartridgeHeader {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x014E..0x0150].copy_from_slice(&global_checksum.to_be_bytes());
+        CartridgeHeader::parse(&CartridgeData(rom)).unwrap()
+    }
+
+    #[test]
+    fn parse_test() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "model": "MGB",
+                "input": { "keys": { "Space": "A", "Key1": "Select" } },
+                "boot_roms": { "DMG": "dmg_boot.bin" },
+                "speed": 2.0
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.model, GameBoyModel::MGB);
+        assert_eq!(config.boot_roms[&GameBoyModel::DMG], "dmg_boot.bin");
+        assert_eq!(config.video.scale, 2);
+        let mapping = config.input.key_mapping().unwrap();
+        assert_eq!(mapping.button(minifb::Key::Key1), Some(Button::Select));
+        assert_eq!(mapping.button(minifb::Key::X), None);
+    }
+    #[test]
+    fn default_input_test() {
+        let mapping = InputConfig::default().key_mapping().unwrap();
+        let bindings: HashMap<_, _> = mapping.bindings().collect();
+        assert_eq!(bindings, KeyMapping::default().bindings().collect());
+        assert_eq!(bindings[&minifb::Key::Backspace], Button::Select);
+        assert_eq!(mapping.rewind_key(), minifb::Key::R);
+    }
+    #[test]
+    fn rewind_key_test() {
+        let config: Config =
+            serde_json::from_str(r#"{ "input": { "keys": { "R": "A" } } }"#).unwrap();
+        assert!(config.input.key_mapping().is_err());
+        let config: Config = serde_json::from_str(
+            r#"{ "input": { "keys": { "R": "A" }, "rewind_key": "Backspace" } }"#,
+        )
+        .unwrap();
+        let mapping = config.input.key_mapping().unwrap();
+        assert_eq!(mapping.rewind_key(), minifb::Key::Backspace);
+        assert_eq!(mapping.button(minifb::Key::R), Some(Button::A));
+    }
+    #[test]
+    fn game_override_test() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "speed": 1.5,
+                "games": {
+                    "TETRIS": { "model": "DMG0", "video": { "scale": 4 } },
+                    "1f3c": { "speed": 3.0 }
+                }
+            }"#,
+        )
+        .unwrap();
+        let tetris = config.for_game(&header("TETRIS", 0x0000));
+        assert_eq!(tetris.model, GameBoyModel::DMG0);
+        assert_eq!(tetris.video.scale, 4);
+        assert_eq!(tetris.speed, 1.5);
+        let other = config.for_game(&header("OTHER", 0x1F3C));
+        assert_eq!(other.speed, 3.0);
+        assert_eq!(other.video.scale, 2);
+        assert_eq!(config.for_game(&header("OTHER", 0x1234)), config);
+    }
+    #[test]
+    fn load_test() {
+        let path = env::temp_dir().join(format!("config_load_test_{}", DEFAULT_CONFIG_FILE));
+        let mut config = Config::default();
+        config.input.keys.insert("Q".to_string(), Button::B);
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+        fs::write(&path, r#"{ "input": { "keys": { "NotAKey": "A" } } }"#).unwrap();
+        assert!(Config::load(&path).is_err());
+        fs::write(&path, r#"{ "games": { "TETRIS": { "speed": 0.0 } } }"#).unwrap();
+        assert!(Config::load(&path).is_err());
+        let huge_limit = format!(r#"{{ "rewind": {{ "memory_limit": {} }} }}"#, usize::MAX);
+        fs::write(&path, huge_limit).unwrap();
+        assert!(matches!(Config::load(&path), Err(Errors::InvalidConfig(_))));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Config::load_or_default(&path).unwrap(), Config::default());
+    }
+}
@@ -1,6 +1,5 @@
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
-use std::time::Instant;
 use std::{cell::RefCell, rc::Rc};
 
 use crate::bus::{InteruptReg, InteruptType};
@@ -13,7 +12,9 @@ use crate::util::math_util::{
 use crate::{
     bus::Bus,
     register::Registers,
+    save_state::{SaveState, StateReader, StateWriter},
     util::{
+        error_type::Errors,
         extract_opcode::{load_json, Instruction},
         math_util::signed_addition,
         opcode_dict_util::{NopreOpcodeMnemonics, NopreOperands, PrefixOpcodeMnemonics},
@@ -25,7 +26,6 @@ pub struct CPU {
     cycles_since_last_cmd: u64,
     cycles_to_wait: u8,
     total_tick: u64,
-    start_time: Instant,
     halt: bool,
 
     ime: bool,
@@ -60,17 +60,31 @@ impl CPU {
             current_interupt: None,
             interupt_stage: 0,
             interupt_happened: false,
-            start_time: Instant::now(),
         }
     }
+    /// Power on state, the log file is kept
+    pub fn reset(&mut self) {
+        let log_buffer = self.log_buffer.take();
+        *self = CPU {
+            log_buffer,
+            ..CPU::new(Rc::clone(&self.bus))
+        };
+    }
+    /// Instructions executed since the cpu was created
+    pub fn instruction_count(&self) -> u64 {
+        self.total_tick
+    }
     // Create a CPU with the register set to the values it should have after boot
     pub fn new_doctor(bus: Rc<RefCell<Bus>>) -> CPU {
         let mut cpu = CPU::new(bus);
         cpu.reg = Registers::new_doctor();
         cpu
     }
-    pub fn init_with_log(&mut self) {
-        self.init_log_file("log/log_file.txt");
+    pub fn init_with_log(&mut self) -> Result<(), Errors> {
+        self.init_with_log_file("log/log_file.txt")
+    }
+    pub fn init_with_log_file(&mut self, file_path: &str) -> Result<(), Errors> {
+        self.init_log_file(file_path)
     }
     fn halt(&mut self) {
         self.halt = true;
@@ -100,58 +114,10 @@ impl CPU {
             if self.log_buffer.is_some() {
                 self.log_state_to_file();
             }
-            if self.total_tick % 300000 == 0 {
-                println!(
-                    "Instruction per milisecond: {}",
-                    self.total_tick as u128 / self.start_time.elapsed().as_millis()
-                )
-            }
-            if self.bus.borrow().read_byte(0x0000) != 60 && self.total_tick < 254076 {
-                // println!("STOP {}", self.total_tick);
-            }
-            if self.total_tick / 31510 == 0 {
-                {
-                    let bus = self.bus.borrow();
-                    println!(
-                        "Timer  div : {}, tima : {}, tma : {}, tac : {}, interupt flag : {}, inter enable : {}",
-                        bus.timer_div_intern,
-                        bus.read_byte(0xFF05),
-                        bus.read_byte(0xFF06),
-                        bus.read_byte(0xFF07),
-                        bus.read_byte(0xFF0F),
-                        bus.read_byte(0xFFFF),
-                    );
-                }
-                let reg = &self.reg;
-                let bus = self.bus.borrow();
-                let text = format!(
-                    "A:{:#04x} F:{:#04x} B:{:#04x} C:{:#04x} D:{:#04x} E:{:#04x} H:{:#04x} L:{:#04x} SP:{:#06x} PC:{:#06x} PCMEM:{:#04x},{:#04x},{:#04x},{:#04x}, ime: {}, tic: {}",
-                    reg.get_a(),
-                    reg.get_f(),
-                    reg.get_b(),
-                    reg.get_c(),
-                    reg.get_d(),
-                    reg.get_e(),
-                    reg.get_h(),
-                    reg.get_l(),
-                    reg.sp,
-                    reg.pc,
-                    bus.read_byte_as_cpu(reg.pc),
-                    bus.read_byte_as_cpu(reg.pc.wrapping_add(1)),
-                    bus.read_byte_as_cpu(reg.pc.wrapping_add(2)),
-                    bus.read_byte_as_cpu(reg.pc.wrapping_add(3)),
-                    self.ime,
-                    self.total_tick
-                );
-                println!("{}", text);
-            }
             // set cycle timing
             self.cycles_since_last_cmd = 0;
             // Run opcode
             self.tick(self.opcode);
-            if self.total_tick / 31510 == 0 {
-                println!("Cycle to wait {}", self.cycles_to_wait);
-            }
             // {
             //     let bus = self.bus.borrow();
             //     println!(
@@ -916,8 +882,6 @@ impl CPU {
             Ok(number) => number,
             Err(_) => panic!("res/set instruction operand not a parsable number"),
         };
-        println!("Instruction : {}", instruction);
-        println!("Bit numbre: {}", bit_number);
         let b = instruction.operands[1].name.clone().into();
         let mut bus = self.bus.borrow_mut();
         let reg = &mut self.reg;
@@ -931,7 +895,7 @@ impl CPU {
             NopreOperands::L => reg.set_l(f(reg.get_l(), bit_number)),
             NopreOperands::HL => {
                 let byte = f(bus.read_byte_as_cpu(reg.hl), bit_number);
-                bus.write_byte(reg.hl, byte);
+                bus.write_byte_as_cpu(reg.hl, byte);
             }
             NopreOperands::INVALID => panic!("Invalid operand"),
             _ => panic!("Missing operand for res/set ?"),
@@ -1057,18 +1021,22 @@ impl CPU {
             self.interupt_happened = true;
         }
     }
-    fn init_log_file(&mut self, file_path: &str) {
+    fn init_log_file(&mut self, file_path: &str) -> Result<(), Errors> {
+        let with_path = |e: io::Error| {
+            Errors::IOError(io::Error::new(e.kind(), format!("{}: {}", file_path, e)))
+        };
         // Open the file with append mode
         let file = OpenOptions::new()
             .write(true)
             .append(true)
             .create(true) // Create the file if it doesn't exist
             .open(file_path)
-            .unwrap();
+            .map_err(with_path)?;
         // Truncate the file to zero length, effectively erasing its contents
-        file.set_len(0).unwrap();
+        file.set_len(0).map_err(with_path)?;
         // Wrap the file in a BufWriter for better performance
         self.log_buffer = Some(io::BufWriter::new(file));
+        Ok(())
     }
     fn log_state_to_file(&mut self) {
         match &mut self.log_buffer {
@@ -1117,6 +1085,49 @@ impl CPU {
 fn get_a8_address(a8: u8) -> u16 {
     return 0xFF00 + a8 as u16;
 }
+// Written instead of an interupt type when none is being serviced
+const NO_INTERUPT: u8 = 0xFF;
+impl SaveState for CPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.reg.save_state(writer);
+        writer.write_u64(self.cycles_since_last_cmd);
+        writer.write_u8(self.cycles_to_wait);
+        writer.write_u64(self.total_tick);
+        writer.write_bool(self.halt);
+        writer.write_bool(self.ime);
+        writer.write_bool(self.interupt_happened);
+        writer.write_u8(self.opcode);
+        writer.write_u8(
+            self.current_interupt
+                .map_or(NO_INTERUPT, |interupt| interupt as u8),
+        );
+        writer.write_u8(self.interupt_stage);
+        writer.write_u16(self.next_pc);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.reg.load_state(reader)?;
+        self.cycles_since_last_cmd = reader.read_u64()?;
+        self.cycles_to_wait = reader.read_u8()?;
+        self.total_tick = reader.read_u64()?;
+        self.halt = reader.read_bool()?;
+        self.ime = reader.read_bool()?;
+        self.interupt_happened = reader.read_bool()?;
+        self.opcode = reader.read_u8()?;
+        self.current_interupt = match reader.read_u8()? {
+            NO_INTERUPT => None,
+            value @ 0..=4 => Some(InteruptType::from(value)),
+            value => {
+                return Err(Errors::InvalidSaveState(format!(
+                    "invalid interupt type {}",
+                    value
+                )))
+            }
+        };
+        self.interupt_stage = reader.read_u8()?;
+        self.next_pc = reader.read_u16()?;
+        Ok(())
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::CPU;
@@ -1135,9 +1146,9 @@ mod tests {
             assert_eq!(slice, &[1, 2, 3]);
         }
         {
-            cpu2.bus.borrow_mut().write_byte_as_cpu(0xFF00, 7);
+            cpu2.bus.borrow_mut().write_byte_as_cpu(0xC000, 7);
             let binding = cpu2.bus.borrow();
-            let slice = binding.read_bytes_range(0xFF00, 3);
+            let slice = binding.read_bytes_range(0xC000, 3);
             assert_eq!(slice, &[7, 0, 0]);
         }
     }
@@ -1,136 +1,409 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::{
     bus::Bus,
+    cartridge::Cartridge,
+    config::Config,
     cpu::CPU,
-    io_handler::IOHandler,
+    joypad::Button,
+    model::GameBoyModel,
     ppu::PPU,
-    quartz::Quartz,
+    register::Registers,
+    rewind::RewindBuffer,
+    save_state::{
+        decode_save_state, encode_save_state, RomChecksum, SaveState, StateReader, StateWriter,
+    },
     timer_reg::TimerReg,
-    util::tiles_util::vram_to_screen,
-    windows::game_window::GameWindow,
+    util::{
+        cartridge_util::{load, load_from_bytes},
+        error_type::Errors,
+        tiles_util::ScreenVector,
+    },
 };
-#[derive(PartialEq, Eq)]
-pub enum EmulatorState {
-    Running,
-    Paused,
-    Stopped,
-}
+
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/// Core of the console, no window, input device nor timing, the frontend drives it
+/// tick by tick and consumes the framebuffer and the audio samples
 pub struct Emulator {
     pub cpu: CPU,
     pub ppu: PPU,
-    pub io_handler: IOHandler,
     pub bus: Rc<RefCell<Bus>>,
-    pub quartz: Quartz,
     pub timer: TimerReg,
-    pub state: EmulatorState,
     pub cycles: u64,
-    pub screen: GameWindow,
-    pub debug_screen: GameWindow,
+    // Called with the new motor state when a rumble cartridge toggles it
+    pub rumble_callback: Option<Box<dyn FnMut(bool)>>,
+    model: GameBoyModel,
+    // Run at power on instead of skipping to the post boot state
+    boot_rom: Option<Vec<u8>>,
+    // Snapshots to go back to, disabled when not set
+    rewind_buffer: Option<RewindBuffer>,
+}
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Emulator {
-    pub fn init(&mut self) {
-        self.screen.init("Main", true);
-        self.debug_screen.init("Debug", false);
+    pub fn new() -> Emulator {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        Emulator {
+            cpu: CPU::new(Rc::clone(&bus)),
+            ppu: PPU::new(Rc::clone(&bus)),
+            timer: TimerReg::new(Rc::clone(&bus)),
+            bus,
+            cycles: 0,
+            rumble_callback: None,
+            model: GameBoyModel::DMG,
+            boot_rom: None,
+            rewind_buffer: None,
+        }
+    }
+    // Start from the boot rom if there is one, otherwise from the state it leaves
+    fn power_on(&mut self) {
+        match &self.boot_rom {
+            Some(boot_rom) => {
+                self.bus.borrow_mut().set_boot_rom(boot_rom.clone());
+                self.cpu.reg = Registers::new();
+            }
+            None => self.set_post_boot_state(self.model),
+        }
+    }
+    /// Power cycle the console, the cartridge and its ram are kept as well as
+    /// the audio capture and the cpu log
+    pub fn reset(&mut self) {
+        let cartridge = self.bus.borrow_mut().cartridge.take();
+        let mut apu = std::mem::take(&mut self.bus.borrow_mut().apu);
+        apu.reset();
+        let mut bus = Bus::new();
+        bus.cartridge = cartridge;
+        bus.apu = apu;
+        *self.bus.borrow_mut() = bus;
+        self.cpu.reset();
+        self.ppu = PPU::new(Rc::clone(&self.bus));
+        self.timer = TimerReg::new(Rc::clone(&self.bus));
+        self.cycles = 0;
+        if let Some(rewind_buffer) = &mut self.rewind_buffer {
+            rewind_buffer.clear();
+        }
+        self.power_on();
+    }
+    /// Load the rom of the config and set the hardware up with the overrides of that game,
+    /// the returned config has those overrides applied for the frontend settings
+    pub fn apply_config(&mut self, config: &Config) -> Result<Config, Errors> {
+        if let Some(rom) = &config.rom {
+            match &config.save_directory {
+                Some(directory) => self
+                    .bus
+                    .borrow_mut()
+                    .load_cartridge_with_save_directory(rom, Path::new(directory))?,
+                None => self.bus.borrow_mut().load_cartridge(rom)?,
+            }
+        }
+        let config = match &self.bus.borrow().cartridge {
+            Some(cartridge) => config.for_game(&cartridge.header),
+            None => config.clone(),
+        };
         self.bus
             .borrow_mut()
-            .load_cartridge("/home/anon/Documents/Code/GameBoyish/roms/Dr. Mario (JU) (V1.1).gb")
-            .unwrap();
-        // Load boot rom
-        // self.bus.borrow_mut().init();
-
-        // Activate logging
-        // self.cpu.init_with_log();
-
-        self.start();
-    }
-    // main emulator loop
-    fn main_loop(&mut self) {
-        if self.state == EmulatorState::Running {
-            loop {
-                self.quartz.wait_till_next_tick();
-                if self.state == EmulatorState::Running {
-                    self.update_emulator_state();
-                }
-                if self.state == EmulatorState::Stopped {
-                    break;
-                }
-            }
+            .apu
+            .set_sample_rate(config.audio.sample_rate);
+        if let Some(capture_file) = &config.audio.capture_file {
+            self.start_audio_capture(Path::new(capture_file), config.audio.capture_channels)?;
         }
+        if let Some(log_file) = &config.log_file {
+            self.cpu.init_with_log_file(log_file)?;
+        }
+        self.model = config.model;
+        self.boot_rom = match config.boot_roms.get(&config.model) {
+            Some(boot_rom) => Some(load(boot_rom)?.0),
+            None => None,
+        };
+        self.power_on();
+        Ok(config)
     }
-    // This function make calls every clock tick
-    fn update_emulator_state(&mut self) {
-        self.quartz.next_tick();
+    /// Advance every component by one clock tick, return true when a frame is complete
+    pub fn next_tick(&mut self) -> bool {
         self.cycles += 1;
-        // TODO: Think where to put this because reading button is made in 2 step
-        // put a bit to set if we want to check direction or buttons
-        // then read the value (How many cycles in between those?)
-        // self.io_handler.next_tick();
         self.cpu.next_tick();
+        self.bus.borrow_mut().next_tick();
+        let rumble = self.bus.borrow_mut().take_rumble_event();
+        if let (Some(on), Some(callback)) = (rumble, &mut self.rumble_callback) {
+            callback(on);
+        }
         self.timer.next_tick();
         self.ppu.next_tick();
-
-        // Screen update at the end of every frame
-        if self.cycles % 70224 == 0 {
-            self.debug_screen.next_tick(&vram_to_screen(
-                Vec::from(self.bus.borrow().read_bytes_range(0x8000, 8192)),
-                16,
+        let frame_complete = self.cycles.is_multiple_of(CYCLES_PER_FRAME);
+        if frame_complete {
+            self.take_rewind_snapshot();
+        }
+        frame_complete
+    }
+    /// Run until the end of the current frame
+    pub fn run_frame(&mut self) {
+        while !self.next_tick() {}
+    }
+    pub fn run_cycles(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.next_tick();
+        }
+    }
+    /// Run until the cpu executes its next instruction, at most a frame if it is halted,
+    /// return the clock ticks it took
+    pub fn step_instruction(&mut self) -> u64 {
+        let instruction_count = self.cpu.instruction_count();
+        let mut cycles = 0;
+        while cycles < CYCLES_PER_FRAME {
+            self.next_tick();
+            cycles += 1;
+            if self.cpu.instruction_count() != instruction_count {
+                break;
+            }
+        }
+        cycles
+    }
+    pub fn frame_count(&self) -> u64 {
+        self.cycles / CYCLES_PER_FRAME
+    }
+    /// Screen drawn by the ppu, 160x144 0xRRGGBB pixels
+    pub fn framebuffer(&self) -> &ScreenVector {
+        &self.ppu.screen_array
+    }
+    /// Stereo samples produced since the last call, at the apu sample rate
+    pub fn take_audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.bus.borrow_mut().apu.take_samples()
+    }
+    /// Skip the boot rom by putting the hardware in the state the model boot rom leaves it
+    pub fn set_post_boot_state(&mut self, model: GameBoyModel) {
+        let header_checksum = match &self.bus.borrow().cartridge {
+            Some(cartridge) => cartridge.header.header_checksum,
+            None => 0,
+        };
+        self.cpu.reg = model.post_boot_registers(header_checksum);
+        self.bus.borrow_mut().set_post_boot_state(model);
+        self.ppu.set_post_boot_position(model);
+    }
+    /// Input from any frontend, the game reads it through 0xFF00
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.bus.borrow_mut().set_button(button, pressed);
+    }
+    /// Press the given buttons and release all the others
+    pub fn set_buttons(&mut self, pressed: &[Button]) {
+        for button in Button::ALL {
+            self.set_button(button, pressed.contains(&button));
+        }
+    }
+    /// Start writing the sound output to a 16-bit stereo wav file, with `per_channel`
+    /// each channel is also written alone to its own file next to it
+    pub fn start_audio_capture(&mut self, path: &Path, per_channel: bool) -> Result<(), Errors> {
+        self.bus.borrow_mut().apu.start_recording(path, per_channel)
+    }
+    pub fn stop_audio_capture(&mut self) -> Result<(), Errors> {
+        self.bus.borrow_mut().apu.stop_recording()
+    }
+    pub fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.rumble_callback = Some(callback);
+    }
+    fn rom_checksum(&self) -> RomChecksum {
+        match &self.bus.borrow().cartridge {
+            Some(cartridge) => RomChecksum {
+                global_checksum: cartridge.header.global_checksum,
+                header_checksum: cartridge.header.header_checksum,
+            },
+            None => RomChecksum {
+                global_checksum: 0,
+                header_checksum: 0,
+            },
+        }
+    }
+    /// Snapshot of the whole console, only loadable with the same rom
+    pub fn save_state(&self) -> Vec<u8> {
+        encode_save_state(self.rom_checksum(), &self.state_payload())
+    }
+    /// Restore a snapshot from `save_state`, states of another rom or format version
+    /// or with an unexpected content are rejected and the console is left as it was
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Errors> {
+        let payload = decode_save_state(state, self.rom_checksum())?;
+        let current_state = self.state_payload();
+        if let Err(e) = self.load_payload(payload) {
+            self.load_payload(&current_state)
+                .expect("the current state can always be restored");
+            return Err(e);
+        }
+        Ok(())
+    }
+    fn state_payload(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u64(self.cycles);
+        self.cpu.save_state(&mut writer);
+        self.bus.borrow().save_state(&mut writer);
+        self.ppu.save_state(&mut writer);
+        writer.into_bytes()
+    }
+    // Components are restored one after another, an error can leave them half restored
+    fn load_payload(&mut self, payload: &[u8]) -> Result<(), Errors> {
+        let mut reader = StateReader::new(payload);
+        self.cycles = reader.read_u64()?;
+        self.cpu.load_state(&mut reader)?;
+        self.bus.borrow_mut().load_state(&mut reader)?;
+        self.ppu.load_state(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(Errors::InvalidSaveState(
+                "unexpected data after the state".to_string(),
             ));
-            self.screen.next_tick(&self.ppu.screen_array);
-            // println!("Screen_array: {:?}", &self.ppu.screen_array);
-            println!("Bg map : {:?}", self.bus.borrow().read_bytes_range(0x9800, 1024));
         }
+        Ok(())
+    }
+    /// Take a save state every `interval` frames to rewind to, the oldest are dropped
+    /// once they use more than `memory_limit` bytes
+    pub fn enable_rewind(&mut self, interval: u64, memory_limit: usize) {
+        self.rewind_buffer = Some(RewindBuffer::new(interval, memory_limit));
+    }
+    pub fn disable_rewind(&mut self) {
+        self.rewind_buffer = None;
+    }
+    fn take_rewind_snapshot(&mut self) {
+        let frame = self.frame_count();
+        if self
+            .rewind_buffer
+            .as_ref()
+            .is_some_and(|rewind_buffer| frame.is_multiple_of(rewind_buffer.interval()))
+        {
+            let state = self.save_state();
+            if let Some(rewind_buffer) = &mut self.rewind_buffer {
+                rewind_buffer.push(frame, state);
+            }
+        }
+    }
+    /// Go back to the newest snapshot at least `frames` frames ago, or to the oldest one,
+    /// return how many frames were rewound
+    pub fn rewind(&mut self, frames: u64) -> Result<u64, Errors> {
+        let current_frame = self.frame_count();
+        let target_frame = current_frame.saturating_sub(frames);
+        let Some(rewind_buffer) = &mut self.rewind_buffer else {
+            return Ok(0);
+        };
+        while rewind_buffer.len() > 1
+            && rewind_buffer
+                .newest()
+                .is_some_and(|(frame, _)| frame > target_frame)
+        {
+            rewind_buffer.pop();
+        }
+        let Some((frame, state)) = rewind_buffer.newest() else {
+            return Ok(0);
+        };
+        let state = state.to_vec();
+        self.load_state(&state)?;
+        Ok(current_frame.saturating_sub(frame))
+    }
+    /// Write the battery save and finish the audio capture, to call before exiting
+    pub fn shutdown(&mut self) {
+        if let Err(e) = self.bus.borrow().save_cartridge() {
+            eprintln!("Failed to write save file: {:?}", e);
+        }
+        if let Err(e) = self.stop_audio_capture() {
+            eprintln!("Failed to write audio capture: {:?}", e);
+        }
+    }
+}
+
+enum RomSource {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
 
-        if self.cycles > 1483180000 {
-            self.pause_resume();
+/// Emulator ready to run a cartridge, for the crates embedding it
+pub struct EmulatorBuilder {
+    rom: Option<RomSource>,
+    model: GameBoyModel,
+    boot_rom: Option<Vec<u8>>,
+    save_data: Option<Vec<u8>>,
+}
+impl Default for EmulatorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl EmulatorBuilder {
+    pub fn new() -> EmulatorBuilder {
+        EmulatorBuilder {
+            rom: None,
+            model: GameBoyModel::DMG,
+            boot_rom: None,
+            save_data: None,
         }
     }
-    fn start(&mut self) {
-        self.state = EmulatorState::Running;
-        self.main_loop();
+    /// Battery saves are read from and written next to the rom file
+    pub fn rom_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.rom = Some(RomSource::File(path.as_ref().to_path_buf()));
+        self
+    }
+    /// Rom without file, optionally in a .zip or .gz archive, battery saves are only kept in memory
+    pub fn rom_bytes(mut self, rom: Vec<u8>) -> Self {
+        self.rom = Some(RomSource::Bytes(rom));
+        self
     }
-    fn stop(&mut self) {
-        self.state = EmulatorState::Stopped;
+    pub fn model(mut self, model: GameBoyModel) -> Self {
+        self.model = model;
+        self
     }
-    fn pause_resume(&mut self) {
-        let state = &self.state;
-        self.state = match state {
-            EmulatorState::Running => EmulatorState::Paused,
-            EmulatorState::Paused => EmulatorState::Running,
-            EmulatorState::Stopped => EmulatorState::Stopped,
+    /// Start from the boot rom instead of the state it leaves
+    pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+    /// Cartridge ram (and rtc) to start with, replaces the save file content
+    pub fn save_data(mut self, save_data: Vec<u8>) -> Self {
+        self.save_data = Some(save_data);
+        self
+    }
+    pub fn build(self) -> Result<Emulator, Errors> {
+        let mut emulator = Emulator::new();
+        match self.rom {
+            Some(RomSource::File(path)) => emulator
+                .bus
+                .borrow_mut()
+                .load_cartridge(&path.to_string_lossy())?,
+            Some(RomSource::Bytes(rom)) => {
+                emulator.bus.borrow_mut().cartridge = Some(Cartridge::new(load_from_bytes(rom)?)?)
+            }
+            None => (),
+        }
+        if let (Some(save_data), Some(cartridge)) =
+            (&self.save_data, &mut emulator.bus.borrow_mut().cartridge)
+        {
+            cartridge.load_save_data(save_data);
         }
+        emulator.model = self.model;
+        emulator.boot_rom = self.boot_rom;
+        emulator.power_on();
+        Ok(emulator)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Emulator, EmulatorState, CPU};
-    use crate::{
-        bus::Bus,
-        io_handler::IOHandler,
-        ppu::PPU,
-        quartz::Quartz,
-        register::Registers,
-        timer_reg::TimerReg,
-        windows::game_window::GameWindow,
-    };
-    use std::{cell::RefCell, rc::Rc};
+    use std::{env, fs};
+
+    use super::{Emulator, EmulatorBuilder, CYCLES_PER_FRAME};
+    use crate::{joypad::Button, model::GameBoyModel, save_state::encode_save_state};
+
+    // Writes 0x42 to 0xC000 then loops on itself
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0100..0x0107].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        rom
+    }
 
     #[test]
     fn multiple_bus_access() {
-        let bus = Rc::new(RefCell::new(Bus::new()));
-        let emu = Emulator {
-            cpu: CPU::new(Rc::clone(&bus)),
-            ppu: PPU::new(Rc::clone(&bus)),
-            io_handler: IOHandler::new(Rc::clone(&bus)),
-            timer: TimerReg::new(Rc::clone(&bus)),
-            bus: Rc::clone(&bus),
-            quartz: Quartz::new(),
-            state: EmulatorState::Running,
-            cycles: 0,
-            screen: GameWindow::new(400, 400),
-            debug_screen: GameWindow::new(500, 500),
-        };
+        let emu = Emulator::new();
+        let bus = &emu.bus;
         {
             bus.borrow_mut().write_slice(0x0010, &[1, 2, 3]);
             let binding = emu.cpu.bus.borrow();
@@ -144,9 +417,9 @@ mod tests {
             assert_eq!(slice, &[1, 2, 3]);
         }
         {
-            bus.borrow_mut().write_byte_as_cpu(0x00A0, 5);
+            bus.borrow_mut().write_byte_as_cpu(0xC0A0, 5);
             let binding = bus.borrow();
-            let val = binding.read_byte_as_cpu(0x00A0);
+            let val = binding.read_byte_as_cpu(0xC0A0);
             assert_eq!(val, 5);
         }
 
@@ -155,4 +428,176 @@ mod tests {
         let val = binding.read_byte_as_cpu(0x8222);
         assert_eq!(val, 2);
     }
+    #[test]
+    fn headless_frame_test() {
+        let mut emu = Emulator::new();
+        let frames_completed = (0..CYCLES_PER_FRAME * 2)
+            .filter(|_| emu.next_tick())
+            .count();
+        assert_eq!(frames_completed, 2);
+        assert_eq!(emu.frame_count(), 2);
+        assert_eq!(emu.framebuffer().pixelcolor_vec.len(), 160 * 144);
+        let samples = emu.take_audio_samples();
+        assert!(!samples.is_empty());
+        assert!(emu.take_audio_samples().is_empty());
+    }
+    #[test]
+    fn builder_test() {
+        let mut emu = EmulatorBuilder::new()
+            .rom_bytes(test_rom())
+            .model(GameBoyModel::MGB)
+            .build()
+            .unwrap();
+        assert_eq!(emu.cpu.reg.pc, 0x0100);
+        assert_eq!(emu.cpu.reg.get_a(), 0xFF);
+        emu.step_instruction();
+        assert_eq!(emu.cpu.reg.pc, 0x0102);
+        assert_eq!(emu.cpu.reg.get_a(), 0x42);
+        emu.step_instruction();
+        assert_eq!(emu.bus.borrow().read_byte(0xC000), 0x42);
+        emu.run_frame();
+        assert_eq!(emu.frame_count(), 1);
+        assert_eq!(emu.cpu.reg.pc, 0x0105);
+        emu.run_cycles(CYCLES_PER_FRAME);
+        assert_eq!(emu.frame_count(), 2);
+
+        emu.set_buttons(&[Button::A, Button::Down]);
+        assert!(emu.bus.borrow().joypad.is_pressed(Button::Down));
+        emu.set_buttons(&[Button::A]);
+        assert!(!emu.bus.borrow().joypad.is_pressed(Button::Down));
+
+        emu.reset();
+        assert_eq!(emu.cycles, 0);
+        assert_eq!(emu.cpu.reg.pc, 0x0100);
+        assert_eq!(emu.bus.borrow().read_byte(0xC000), 0x00);
+        assert!(!emu.bus.borrow().joypad.is_pressed(Button::A));
+        assert!(emu.bus.borrow().cartridge.is_some());
+    }
+    #[test]
+    fn reset_capture_test() {
+        let path = env::temp_dir().join("game_boyish_reset_capture_test.wav");
+        let mut emu = EmulatorBuilder::new()
+            .rom_bytes(test_rom())
+            .build()
+            .unwrap();
+        emu.start_audio_capture(&path, false).unwrap();
+        emu.run_frame();
+        emu.reset();
+        emu.run_frame();
+        emu.stop_audio_capture().unwrap();
+        // The header holds the size of the samples of both frames
+        let data = fs::read(&path).unwrap();
+        let data_size = u32::from_le_bytes(data[40..44].try_into().unwrap()) as usize;
+        assert_eq!(data_size, data.len() - 44);
+        assert!(data_size > 4 * 44_100 / 60);
+        fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn builder_boot_rom_test() {
+        let mut emu = EmulatorBuilder::new()
+            .rom_bytes(test_rom())
+            .boot_rom(vec![0x00; 0x100])
+            .build()
+            .unwrap();
+        assert_eq!(emu.cpu.reg.pc, 0x0000);
+        assert!(emu.bus.borrow().is_boot_rom_mapped());
+        emu.step_instruction();
+        assert_eq!(emu.cpu.reg.pc, 0x0001);
+        assert!(EmulatorBuilder::new()
+            .rom_bytes(vec![0; 0x10])
+            .build()
+            .is_err());
+    }
+    #[test]
+    fn save_state_test() {
+        let mut emu = EmulatorBuilder::new()
+            .rom_bytes(test_rom())
+            .build()
+            .unwrap();
+        emu.run_cycles(CYCLES_PER_FRAME + 1234);
+        let state = emu.save_state();
+        let registers = (emu.cpu.reg.get_af(), emu.cpu.reg.pc, emu.cpu.reg.sp);
+        let cycles = emu.cycles;
+        emu.run_frame();
+        let framebuffer = emu.framebuffer().pixelcolor_vec.clone();
+
+        emu.bus.borrow_mut().write_byte(0xC000, 0x00);
+        emu.run_cycles(5000);
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.cycles, cycles);
+        assert_eq!(
+            (emu.cpu.reg.get_af(), emu.cpu.reg.pc, emu.cpu.reg.sp),
+            registers
+        );
+        assert_eq!(emu.bus.borrow().read_byte(0xC000), 0x42);
+        // Running from the restored state gives the same frame
+        emu.run_frame();
+        assert_eq!(emu.framebuffer().pixelcolor_vec, framebuffer);
+        assert_eq!(emu.save_state().len(), state.len());
+
+        let mut other_rom = test_rom();
+        other_rom[0x014E] = 0x12;
+        let mut other = EmulatorBuilder::new().rom_bytes(other_rom).build().unwrap();
+        assert!(other.load_state(&state).is_err());
+        assert!(emu.load_state(&state[..state.len() / 2]).is_err());
+    }
+    #[test]
+    fn invalid_save_state_test() {
+        let mut emu = EmulatorBuilder::new()
+            .rom_bytes(test_rom())
+            .build()
+            .unwrap();
+        emu.run_frame();
+        let payload = emu.state_payload();
+        emu.bus.borrow_mut().write_byte(0xC001, 0x77);
+        emu.run_frame();
+        let current_state = emu.save_state();
+
+        // Valid header and checksum but the payload stops in the middle of the ppu
+        let truncated = encode_save_state(emu.rom_checksum(), &payload[..payload.len() - 100]);
+        assert!(emu.load_state(&truncated).is_err());
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        let trailing = encode_save_state(emu.rom_checksum(), &trailing);
+        assert!(emu.load_state(&trailing).is_err());
+
+        assert_eq!(emu.frame_count(), 2);
+        assert_eq!(emu.bus.borrow().read_byte(0xC001), 0x77);
+        assert_eq!(emu.save_state(), current_state);
+    }
+    #[test]
+    fn rewind_test() {
+        let mut emu = EmulatorBuilder::new()
+            .rom_bytes(test_rom())
+            .build()
+            .unwrap();
+        assert_eq!(emu.rewind(1).unwrap(), 0);
+        emu.enable_rewind(1, 1 << 24);
+        for _ in 0..3 {
+            emu.run_frame();
+        }
+        emu.bus.borrow_mut().write_byte(0xC001, 0x99);
+        emu.run_frame();
+        emu.run_frame();
+        assert_eq!(emu.bus.borrow().read_byte(0xC001), 0x99);
+
+        assert_eq!(emu.rewind(2).unwrap(), 2);
+        assert_eq!(emu.frame_count(), 3);
+        assert_eq!(emu.bus.borrow().read_byte(0xC001), 0x00);
+        assert_eq!(emu.bus.borrow().read_byte(0xC000), 0x42);
+        // Past the oldest snapshot it stops at the first frame
+        assert_eq!(emu.rewind(10).unwrap(), 2);
+        assert_eq!(emu.frame_count(), 1);
+        emu.run_frame();
+        assert_eq!(emu.frame_count(), 2);
+
+        // The buffer keeps the newest snapshots within the memory limit
+        emu.enable_rewind(2, 0);
+        for _ in 0..6 {
+            emu.run_frame();
+        }
+        assert_eq!(emu.rewind(1).unwrap(), 0);
+        assert_eq!(emu.rewind(3).unwrap(), 0);
+        assert_eq!(emu.frame_count(), 8);
+    }
 }
@@ -0,0 +1,119 @@
+use std::rc::Rc;
+
+use crate::{
+    config::Config,
+    emulator::Emulator,
+    io_handler::IOHandler,
+    quartz::Quartz,
+    util::{error_type::Errors, tiles_util::vram_to_screen},
+    windows::game_window::{GameWindow, GAMEBOY_SCREEN_HEIGHT, GAMEBOY_SCREEN_WIDTH},
+};
+
+#[derive(PartialEq, Eq)]
+pub enum EmulatorState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+/// Desktop frontend, runs the emulator at the console speed in a minifb window
+/// with keyboard and gamepad input
+pub struct WindowFrontend {
+    pub emulator: Emulator,
+    pub io_handler: IOHandler,
+    pub quartz: Quartz,
+    pub state: EmulatorState,
+    pub screen: GameWindow,
+    pub debug_screen: GameWindow,
+    // Stop after this many frames
+    pub frame_limit: Option<u64>,
+}
+impl WindowFrontend {
+    pub fn new(emulator: Emulator) -> WindowFrontend {
+        WindowFrontend {
+            io_handler: IOHandler::new(Rc::clone(&emulator.bus)),
+            emulator,
+            quartz: Quartz::with_speed(1.0),
+            state: EmulatorState::Stopped,
+            screen: GameWindow::new(GAMEBOY_SCREEN_WIDTH * 2, GAMEBOY_SCREEN_HEIGHT * 2),
+            debug_screen: GameWindow::new(128 * 2, 192 * 2),
+            frame_limit: None,
+        }
+    }
+    /// Set the emulator up from the config, open the windows and run until they are closed
+    pub fn init(&mut self, config: &Config) -> Result<(), Errors> {
+        let config = self.emulator.apply_config(config)?;
+        self.io_handler.key_mapping = config.input.key_mapping()?;
+        self.screen.set_scale(config.video.scale);
+        self.screen.set_palette(config.video.palette);
+        self.quartz = Quartz::with_speed(config.speed);
+        if config.rewind.interval > 0 {
+            let memory_limit = config.rewind.memory_limit_bytes()?;
+            self.emulator.enable_rewind(config.rewind.interval, memory_limit);
+        }
+
+        self.screen.init("Main", true);
+        self.debug_screen.init("Debug", false);
+        self.io_handler.start_gamepad_listener();
+
+        self.start();
+        Ok(())
+    }
+    // main emulator loop
+    fn main_loop(&mut self) {
+        if self.state == EmulatorState::Running {
+            loop {
+                self.quartz.wait_till_next_tick();
+                if self.state == EmulatorState::Running {
+                    self.update_emulator_state();
+                }
+                if self.state == EmulatorState::Stopped {
+                    break;
+                }
+            }
+        }
+    }
+    // This function make calls every clock tick
+    fn update_emulator_state(&mut self) {
+        self.quartz.next_tick();
+        // Screen update at the end of every frame
+        if self.emulator.next_tick() {
+            let keys = self.screen.get_keys();
+            // The frame just run is undone too, so the game goes back a frame per frame
+            if keys.contains(&self.io_handler.key_mapping.rewind_key()) {
+                if let Err(e) = self.emulator.rewind(2) {
+                    eprintln!("Failed to rewind: {}", e);
+                }
+            }
+            self.debug_screen.next_tick(&vram_to_screen(
+                Vec::from(self.emulator.bus.borrow().read_bytes_range(0x8000, 8192)),
+                16,
+            ));
+            self.screen.next_tick(self.emulator.framebuffer());
+            self.io_handler.update_buttons(&keys);
+
+            let frame_limit_reached = self
+                .frame_limit
+                .is_some_and(|frames| self.emulator.frame_count() >= frames);
+            if frame_limit_reached || !self.screen.is_open() {
+                self.stop();
+            }
+        }
+    }
+    fn start(&mut self) {
+        self.state = EmulatorState::Running;
+        self.main_loop();
+    }
+    fn stop(&mut self) {
+        self.state = EmulatorState::Stopped;
+        self.emulator.shutdown();
+    }
+    pub fn pause_resume(&mut self) {
+        let state = &self.state;
+        self.state = match state {
+            EmulatorState::Running => EmulatorState::Paused,
+            EmulatorState::Paused => EmulatorState::Running,
+            EmulatorState::Stopped => EmulatorState::Stopped,
+        }
+    }
+}
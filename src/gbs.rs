@@ -0,0 +1,318 @@
+use std::{cell::RefCell, fs, rc::Rc};
+
+use crate::{
+    bus::Bus,
+    cartridge::{Cartridge, MBC, RAM_BANK_SIZE, ROM_BANK_SIZE},
+    cpu::CPU,
+    model::GameBoyModel,
+    save_state::{SaveState, StateReader, StateWriter},
+    timer_reg::TimerReg,
+    util::{
+        cartridge_util::{CartridgeData, CartridgeHeader},
+        error_type::Errors,
+    },
+};
+
+const GBS_HEADER_SIZE: usize = 0x70;
+const GBS_MAGIC: &[u8; 3] = b"GBS";
+// Routines return to this infinite loop (JR -2) placed before the lowest load address
+const IDLE_ADDRESS: u16 = 0x00F0;
+const VBLANK_PERIOD: u32 = 70224;
+// Clock ticks per timer increment for each TAC clock select
+const TIMER_PERIODS: [u32; 4] = [1024, 16, 64, 256];
+
+/// Header of a Game Boy Sound System file, the music code follows it
+#[derive(Debug)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    // 1 based
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<GbsHeader, Errors> {
+        if data.len() < GBS_HEADER_SIZE || &data[0..3] != GBS_MAGIC {
+            return Err(Errors::InvalidGbs("missing GBS header".to_string()));
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let text = |offset: usize| -> String {
+            data[offset..offset + 32]
+                .iter()
+                .take_while(|&&byte| byte != 0)
+                .map(|&byte| char::from(byte))
+                .collect()
+        };
+        let header = GbsHeader {
+            version: data[0x03],
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        };
+        if header.load_address < 0x0400 || header.load_address >= 0x8000 {
+            return Err(Errors::InvalidGbs(format!(
+                "load address {:#06x} outside of 0x0400-0x7FFF",
+                header.load_address
+            )));
+        }
+        Ok(header)
+    }
+    /// Clock ticks between two calls of the play routine, the timer rate if the
+    /// timer is enabled otherwise the vblank rate
+    pub fn play_period(&self) -> u32 {
+        if self.timer_control & 0x04 == 0 {
+            return VBLANK_PERIOD;
+        }
+        let period =
+            TIMER_PERIODS[(self.timer_control & 0x03) as usize] * (256 - self.timer_modulo as u32);
+        // Bit 7 asks for the CGB double speed mode
+        match self.timer_control & 0x80 {
+            0 => period,
+            _ => period / 2,
+        }
+    }
+}
+impl std::fmt::Display for GbsHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Author: {}", self.author)?;
+        writeln!(f, "Copyright: {}", self.copyright)?;
+        write!(
+            f,
+            "Songs: {} (first {}), load: {:#06x}, init: {:#06x}, play: {:#06x}",
+            self.song_count,
+            self.first_song,
+            self.load_address,
+            self.init_address,
+            self.play_address
+        )
+    }
+}
+
+/// Banking of the gbs rips, MBC1 like rom bank register and always enabled ram
+struct GbsMapper {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank: u8,
+}
+impl MBC for GbsMapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => self.rom_bank as usize * ROM_BANK_SIZE + (address - 0x4000) as usize,
+        };
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+    fn write_rom(&mut self, address: u16, value: u8) {
+        if (0x2000..=0x3FFF).contains(&address) {
+            self.rom_bank = value.max(1);
+        }
+    }
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram[(address - 0xA000) as usize]
+    }
+    fn write_ram(&mut self, address: u16, value: u8) {
+        self.ram[(address - 0xA000) as usize] = value;
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+impl SaveState for GbsMapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.rom_bank);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.rom_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/// Play the tracks of a gbs file through the cpu and apu, without ppu
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    pub cpu: CPU,
+    pub bus: Rc<RefCell<Bus>>,
+    timer: TimerReg,
+    // Rom image with the code at its load address and the player routines below it
+    rom: Vec<u8>,
+    track: u8,
+    cycles_to_play: u32,
+}
+impl GbsPlayer {
+    pub fn new(data: &[u8]) -> Result<GbsPlayer, Errors> {
+        let header = GbsHeader::parse(data)?;
+        let rom = gbs_rom_image(&header, &data[GBS_HEADER_SIZE..]);
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut player = GbsPlayer {
+            cpu: CPU::new(Rc::clone(&bus)),
+            timer: TimerReg::new(Rc::clone(&bus)),
+            bus,
+            rom,
+            track: 0,
+            cycles_to_play: 0,
+            header,
+        };
+        player.start_track(player.header.first_song.saturating_sub(1))?;
+        Ok(player)
+    }
+    pub fn load(path: &str) -> Result<GbsPlayer, Errors> {
+        GbsPlayer::new(&fs::read(path)?)
+    }
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+    /// Reset the hardware and call the init routine with the track number (0 based)
+    pub fn start_track(&mut self, track: u8) -> Result<(), Errors> {
+        if track >= self.header.song_count {
+            return Err(Errors::InvalidGbs(format!(
+                "track {} out of {} tracks",
+                track, self.header.song_count
+            )));
+        }
+        let data = CartridgeData(self.rom.clone());
+        let header = CartridgeHeader::parse(&data)?;
+        let mapper = GbsMapper {
+            rom: data.0,
+            ram: vec![0u8; RAM_BANK_SIZE],
+            rom_bank: 1,
+        };
+        let cartridge = Cartridge::with_mbc(header, Box::new(mapper));
+        let sample_rate = self.bus.borrow().apu.sample_rate();
+        let mut bus = Bus::new();
+        bus.cartridge = Some(cartridge);
+        bus.set_post_boot_state(GameBoyModel::DMG);
+        bus.apu.set_sample_rate(sample_rate);
+        bus.write_byte(0xFF06, self.header.timer_modulo);
+        bus.write_byte(0xFF07, self.header.timer_control);
+        *self.bus.borrow_mut() = bus;
+
+        self.cpu = CPU::new(Rc::clone(&self.bus));
+        self.cpu.reg = GameBoyModel::DMG.post_boot_registers(0);
+        self.cpu.reg.sp = self.header.stack_pointer;
+        self.cpu.reg.set_a(track);
+        self.call(self.header.init_address);
+        self.track = track;
+        self.cycles_to_play = self.header.play_period();
+        Ok(())
+    }
+    // Call a routine of the gbs code, it returns to the idle loop
+    fn call(&mut self, address: u16) {
+        let reg = &mut self.cpu.reg;
+        reg.sp = reg.sp.wrapping_sub(2);
+        self.bus
+            .borrow_mut()
+            .write_2_bytes_little_endian(reg.sp, IDLE_ADDRESS);
+        reg.pc = address;
+    }
+    pub fn next_tick(&mut self) {
+        self.cpu.next_tick();
+        self.bus.borrow_mut().next_tick();
+        self.timer.next_tick();
+
+        self.cycles_to_play -= 1;
+        if self.cycles_to_play == 0 {
+            self.cycles_to_play = self.header.play_period();
+            // Skip this call if the previous routine is still running
+            if self.cpu.reg.pc == IDLE_ADDRESS {
+                self.call(self.header.play_address);
+            }
+        }
+    }
+    pub fn run_cycles(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.next_tick();
+        }
+    }
+    /// Stereo samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        self.bus.borrow_mut().apu.take_samples()
+    }
+}
+
+// Code mapped at its load address, RST vectors jumping to load address + vector
+// and interrupt vectors returning right away as the player calls the routines itself
+fn gbs_rom_image(header: &GbsHeader, code: &[u8]) -> Vec<u8> {
+    let load_address = header.load_address as usize;
+    let size = (load_address + code.len())
+        .next_multiple_of(ROM_BANK_SIZE)
+        .max(2 * ROM_BANK_SIZE);
+    let mut rom = vec![0u8; size];
+    rom[load_address..load_address + code.len()].copy_from_slice(code);
+    for vector in (0x00..=0x38).step_by(8) {
+        let [low, high] = (header.load_address + vector).to_le_bytes();
+        rom[vector as usize..vector as usize + 3].copy_from_slice(&[0xC3, low, high]);
+    }
+    for vector in (0x40..=0x60).step_by(8) {
+        rom[vector] = 0xD9;
+    }
+    rom[IDLE_ADDRESS as usize..IDLE_ADDRESS as usize + 2].copy_from_slice(&[0x18, 0xFE]);
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GbsHeader, GbsPlayer, IDLE_ADDRESS, VBLANK_PERIOD};
+
+    // Init stores the track number at 0xC000, play increments 0xC001
+    fn test_gbs(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0u8; 0x70];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3;
+        data[0x05] = 1;
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x0404u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x14].copy_from_slice(b"Test");
+        data.extend_from_slice(&[0xEA, 0x00, 0xC0, 0xC9]);
+        data.extend_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+        data
+    }
+
+    #[test]
+    fn header_test() {
+        let header = GbsHeader::parse(&test_gbs(0, 0)).unwrap();
+        assert_eq!(header.title, "Test");
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.play_address, 0x0404);
+        assert_eq!(header.play_period(), VBLANK_PERIOD);
+        // Timer at 4096Hz (1024 ticks) overflowing every 0x100 - 0xC0 increments
+        let header = GbsHeader::parse(&test_gbs(0xC0, 0x04)).unwrap();
+        assert_eq!(header.play_period(), 1024 * 0x40);
+        assert!(GbsHeader::parse(b"GBX").is_err());
+    }
+    #[test]
+    fn play_test() {
+        let mut player = GbsPlayer::new(&test_gbs(0, 0)).unwrap();
+        player.start_track(2).unwrap();
+        player.run_cycles(VBLANK_PERIOD * 3 + 1000);
+        let bus = player.bus.borrow();
+        assert_eq!(bus.read_byte(0xC000), 2);
+        assert_eq!(bus.read_byte(0xC001), 3);
+        assert_eq!(player.cpu.reg.pc, IDLE_ADDRESS);
+        drop(bus);
+        assert!(player.start_track(3).is_err());
+    }
+}
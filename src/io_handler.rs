@@ -1,39 +1,222 @@
-extern crate evdev;
-use std::{cell::RefCell, rc::Rc};
+pub mod gamepad;
 
-use crate::bus::Bus;
-use evdev::{Device, EventType, Key};
+use std::{cell::RefCell, collections::HashMap, path::PathBuf, rc::Rc};
+
+use crate::{bus::Bus, joypad::Button, util::u8_traits::Bit};
+use gamepad::GamepadListener;
+use minifb::Key;
+
+/// Keyboard keys bound to each console button, several keys can share a button,
+/// and the key held to rewind
+pub struct KeyMapping {
+    buttons: HashMap<Key, Button>,
+    rewind_key: Key,
+}
+impl Default for KeyMapping {
+    fn default() -> Self {
+        let mut mapping = KeyMapping::empty();
+        mapping.bind(Key::Up, Button::Up);
+        mapping.bind(Key::Down, Button::Down);
+        mapping.bind(Key::Left, Button::Left);
+        mapping.bind(Key::Right, Button::Right);
+        mapping.bind(Key::X, Button::A);
+        mapping.bind(Key::Z, Button::B);
+        mapping.bind(Key::Enter, Button::Start);
+        mapping.bind(Key::Backspace, Button::Select);
+        mapping
+    }
+}
+impl KeyMapping {
+    /// No button bound, the rewind key is R
+    pub fn empty() -> KeyMapping {
+        KeyMapping {
+            buttons: HashMap::new(),
+            rewind_key: Key::R,
+        }
+    }
+    pub fn bind(&mut self, key: Key, button: Button) {
+        self.buttons.insert(key, button);
+    }
+    pub fn unbind(&mut self, key: Key) {
+        self.buttons.remove(&key);
+    }
+    pub fn button(&self, key: Key) -> Option<Button> {
+        self.buttons.get(&key).copied()
+    }
+    pub fn bindings(&self) -> impl Iterator<Item = (Key, Button)> + '_ {
+        self.buttons.iter().map(|(&key, &button)| (key, button))
+    }
+    pub fn rewind_key(&self) -> Key {
+        self.rewind_key
+    }
+    pub fn set_rewind_key(&mut self, key: Key) {
+        self.rewind_key = key;
+    }
+}
+
+const LETTER_KEYS: [Key; 26] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+];
+const DIGIT_KEYS: [Key; 10] = [
+    Key::Key0,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+];
+
+/// Key from the name of its minifb variant ("A", "Key1", "Enter", "LeftShift"...)
+pub fn key_from_name(name: &str) -> Option<Key> {
+    let key = match name {
+        "Up" => Key::Up,
+        "Down" => Key::Down,
+        "Left" => Key::Left,
+        "Right" => Key::Right,
+        "Enter" => Key::Enter,
+        "Space" => Key::Space,
+        "Backspace" => Key::Backspace,
+        "Tab" => Key::Tab,
+        "Escape" => Key::Escape,
+        "LeftShift" => Key::LeftShift,
+        "RightShift" => Key::RightShift,
+        "LeftCtrl" => Key::LeftCtrl,
+        "RightCtrl" => Key::RightCtrl,
+        "LeftAlt" => Key::LeftAlt,
+        "RightAlt" => Key::RightAlt,
+        _ => {
+            let mut chars = name.chars();
+            return match (chars.next(), chars.next(), name.strip_prefix("Key")) {
+                (Some(letter @ 'A'..='Z'), None, _) => {
+                    Some(LETTER_KEYS[(letter as u8 - b'A') as usize])
+                }
+                (_, _, Some(digit)) => digit
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|digit| DIGIT_KEYS.get(digit).copied()),
+                _ => None,
+            };
+        }
+    };
+    Some(key)
+}
+
+/// Name of the key as accepted by `key_from_name`
+pub fn key_name(key: Key) -> String {
+    format!("{:?}", key)
+}
 
 pub struct IOHandler {
     pub bus: Rc<RefCell<Bus>>,
-    device: Device,
+    pub key_mapping: KeyMapping,
+    gamepad_listener: Option<GamepadListener>,
+    // Buttons held on each connected gamepad
+    gamepads: HashMap<PathBuf, u8>,
 }
 impl IOHandler {
     pub fn new(bus: Rc<RefCell<Bus>>) -> IOHandler {
-        let path = "/dev/input/event16"; // Replace X with the appropriate event number
-        let device = Device::open(path).expect("Failed to create device");
-        IOHandler { bus, device }
-    }
-    pub fn next_tick(&mut self) {
-        //TODO: Run this in a separate thread to avoid blocking the emulator
-        if let Ok(events) = self.device.fetch_events() {
-            for event in events {
-                if event.event_type() == EventType::KEY {
-                    match event.value() {
-                        1 => {
-                            println!("Press");
-                            println!("event : {}", event.code())
-                        }
-                        _ => {}
-                    }
-                    if event.code() == Key::KEY_END.code() {}
-                    if event.code() == Key::KEY_0.code() {}
-                }
+        IOHandler::with_key_mapping(bus, KeyMapping::default())
+    }
+    pub fn with_key_mapping(bus: Rc<RefCell<Bus>>, key_mapping: KeyMapping) -> IOHandler {
+        IOHandler {
+            bus,
+            key_mapping,
+            gamepad_listener: None,
+            gamepads: HashMap::new(),
+        }
+    }
+    /// Look for evdev gamepads, plugged now or later, on a background thread
+    pub fn start_gamepad_listener(&mut self) {
+        if self.gamepad_listener.is_none() {
+            self.gamepad_listener = Some(GamepadListener::start());
+        }
+    }
+    /// Set every button from the keys currently held down in the window
+    /// and the buttons held on the gamepads
+    pub fn update_buttons(&mut self, keys: &[Key]) {
+        if let Some(listener) = &self.gamepad_listener {
+            for event in listener.poll() {
+                match event.pressed {
+                    0 => self.gamepads.remove(&event.path),
+                    pressed => self.gamepads.insert(event.path, pressed),
+                };
+            }
+        }
+        let gamepad_pressed = self
+            .gamepads
+            .values()
+            .fold(0, |mask, pressed| mask | pressed);
+        let mut bus = self.bus.borrow_mut();
+        for button in Button::ALL {
+            let pressed = gamepad_pressed.get_bit(button as u8)
+                || keys
+                    .iter()
+                    .any(|&key| self.key_mapping.button(key) == Some(button));
+            if pressed != bus.joypad.is_pressed(button) {
+                bus.set_button(button, pressed);
             }
         }
     }
 }
 
-fn tick() {
-    loop {}
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use minifb::Key;
+
+    use super::{key_from_name, IOHandler, KeyMapping};
+    use crate::{bus::Bus, joypad::Button};
+
+    #[test]
+    fn key_mapping_test() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let mut mapping = KeyMapping::default();
+        mapping.bind(Key::Space, Button::A);
+        mapping.unbind(Key::Enter);
+        let mut io_handler = IOHandler::with_key_mapping(Rc::clone(&bus), mapping);
+        io_handler.update_buttons(&[Key::Space, Key::Enter, Key::Up]);
+        assert!(bus.borrow().joypad.is_pressed(Button::A));
+        assert!(bus.borrow().joypad.is_pressed(Button::Up));
+        assert!(!bus.borrow().joypad.is_pressed(Button::Start));
+        io_handler.update_buttons(&[Key::Up]);
+        assert!(!bus.borrow().joypad.is_pressed(Button::A));
+    }
+    #[test]
+    fn key_from_name_test() {
+        assert_eq!(key_from_name("Q"), Some(Key::Q));
+        assert_eq!(key_from_name("Key7"), Some(Key::Key7));
+        assert_eq!(key_from_name("RightShift"), Some(Key::RightShift));
+        assert_eq!(key_from_name("Key10"), None);
+        assert_eq!(key_from_name("q"), None);
+    }
 }
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use evdev::{AbsoluteAxisType, Device, InputEventKind, Key};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+
+use crate::{joypad::Button, util::u8_traits::Bit};
+
+const INPUT_DEVICE_DIRECTORY: &str = "/dev/input";
+// Time between two scans of /dev/input for new gamepads
+const DISCOVERY_PERIOD: Duration = Duration::from_secs(1);
+// Longest wait for a gamepad event before a reader checks if the listener is gone
+const READ_TIMEOUT_MS: i32 = 100;
+// Part of the half range an analog stick has to move before it counts as a direction
+const STICK_DEAD_ZONE: f32 = 0.5;
+// Hat axes report -1, 0 or 1
+const HAT_THRESHOLDS: AxisThresholds = AxisThresholds { low: 0, high: 0 };
+
+/// Buttons held on one gamepad, as a mask of the joypad button bits.
+/// A disconnected gamepad sends an empty mask
+pub struct GamepadEvent {
+    pub path: PathBuf,
+    pub pressed: u8,
+}
+
+/// Find gamepads and read their events on separate threads, the events are
+/// collected without blocking by `poll`
+pub struct GamepadListener {
+    receiver: Receiver<GamepadEvent>,
+    // Set when the listener is dropped to end the discovery and reader threads
+    stop: Arc<AtomicBool>,
+}
+impl GamepadListener {
+    pub fn start() -> GamepadListener {
+        let (sender, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let discovery_stop = Arc::clone(&stop);
+        thread::spawn(move || discover_gamepads(sender, discovery_stop));
+        GamepadListener { receiver, stop }
+    }
+    /// Events received since the last call
+    pub fn poll(&self) -> Vec<GamepadEvent> {
+        self.receiver.try_iter().collect()
+    }
+}
+impl Drop for GamepadListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// Scan /dev/input periodically and start a reader for each new gamepad, only the
+// devices not seen before are opened. Readers remove their device from the opened
+// set when it is unplugged, the other devices are forgotten once their node is gone
+fn discover_gamepads(sender: Sender<GamepadEvent>, stop: Arc<AtomicBool>) {
+    let opened = Arc::new(Mutex::new(HashSet::new()));
+    let mut not_gamepads = HashSet::new();
+    while !stop.load(Ordering::Relaxed) {
+        let paths = input_device_paths();
+        not_gamepads.retain(|path| paths.contains(path));
+        for path in paths {
+            if not_gamepads.contains(&path) || opened.lock().unwrap().contains(&path) {
+                continue;
+            }
+            let Ok(device) = Device::open(&path) else {
+                continue;
+            };
+            if !is_gamepad(&device) {
+                not_gamepads.insert(path);
+                continue;
+            }
+            opened.lock().unwrap().insert(path.clone());
+            let sender = sender.clone();
+            let opened = Arc::clone(&opened);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                read_gamepad(&path, device, &sender, &stop);
+                opened.lock().unwrap().remove(&path);
+                let _ = sender.send(GamepadEvent { path, pressed: 0 });
+            });
+        }
+        thread::sleep(DISCOVERY_PERIOD);
+    }
+}
+
+// Event nodes of the input devices, without opening them
+fn input_device_paths() -> HashSet<PathBuf> {
+    let Ok(entries) = fs::read_dir(INPUT_DEVICE_DIRECTORY) else {
+        return HashSet::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+// Gamepads have face buttons, keyboards and mice don't report BTN_SOUTH
+fn is_gamepad(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::BTN_SOUTH))
+}
+
+// Wait for the device events until it is unplugged or the listener is dropped,
+// the state is sent after each sync report
+fn read_gamepad(path: &Path, mut device: Device, sender: &Sender<GamepadEvent>, stop: &AtomicBool) {
+    let mut state = GamepadState::new(&device);
+    let mut last_pressed = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(device.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, READ_TIMEOUT_MS) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => {}
+            Err(_) => return,
+        }
+        // Fails once the device is unplugged
+        let Ok(events) = device.fetch_events() else {
+            return;
+        };
+        for event in events {
+            match event.kind() {
+                InputEventKind::Key(key) => state.set_key(key, event.value() != 0),
+                InputEventKind::AbsAxis(axis) => state.set_axis(axis, event.value()),
+                _ => {}
+            }
+        }
+        let pressed = state.pressed();
+        if pressed != last_pressed {
+            last_pressed = pressed;
+            let event = GamepadEvent {
+                path: path.to_path_buf(),
+                pressed,
+            };
+            if sender.send(event).is_err() {
+                // The emulator is gone
+                return;
+            }
+        }
+    }
+}
+
+fn key_button(key: Key) -> Option<Button> {
+    match key {
+        // Same position as on the console, the right face button is A
+        Key::BTN_EAST => Some(Button::A),
+        Key::BTN_SOUTH => Some(Button::B),
+        Key::BTN_SELECT => Some(Button::Select),
+        Key::BTN_START => Some(Button::Start),
+        Key::BTN_DPAD_UP => Some(Button::Up),
+        Key::BTN_DPAD_DOWN => Some(Button::Down),
+        Key::BTN_DPAD_LEFT => Some(Button::Left),
+        Key::BTN_DPAD_RIGHT => Some(Button::Right),
+        _ => None,
+    }
+}
+
+/// Values beyond which an analog stick axis is pushed in a direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AxisThresholds {
+    low: i32,
+    high: i32,
+}
+impl AxisThresholds {
+    fn new(minimum: i32, maximum: i32) -> AxisThresholds {
+        let center = (minimum + maximum) / 2;
+        let reach = ((maximum - minimum) as f32 / 2.0 * STICK_DEAD_ZONE) as i32;
+        AxisThresholds {
+            low: center - reach,
+            high: center + reach,
+        }
+    }
+    // Mask of the negative and positive direction buttons held for this value
+    fn directions(&self, value: i32, negative: Button, positive: Button) -> u8 {
+        let mut pressed = 0;
+        pressed.set_bit(negative as u8, value < self.low);
+        pressed.set_bit(positive as u8, value > self.high);
+        pressed
+    }
+}
+
+/// Directions come from the buttons, the hat and the left stick, each kept
+/// apart so releasing one doesn't release a direction held on another
+struct GamepadState {
+    buttons: u8,
+    hat: u8,
+    stick: u8,
+    stick_x: AxisThresholds,
+    stick_y: AxisThresholds,
+}
+impl GamepadState {
+    fn new(device: &Device) -> GamepadState {
+        let (stick_x, stick_y) = match device.get_abs_state() {
+            Ok(abs) => {
+                let x = abs[AbsoluteAxisType::ABS_X.0 as usize];
+                let y = abs[AbsoluteAxisType::ABS_Y.0 as usize];
+                (
+                    AxisThresholds::new(x.minimum, x.maximum),
+                    AxisThresholds::new(y.minimum, y.maximum),
+                )
+            }
+            Err(_) => (AxisThresholds::new(-1, 1), AxisThresholds::new(-1, 1)),
+        };
+        GamepadState::with_thresholds(stick_x, stick_y)
+    }
+    fn with_thresholds(stick_x: AxisThresholds, stick_y: AxisThresholds) -> GamepadState {
+        GamepadState {
+            buttons: 0,
+            hat: 0,
+            stick: 0,
+            stick_x,
+            stick_y,
+        }
+    }
+    fn set_key(&mut self, key: Key, pressed: bool) {
+        if let Some(button) = key_button(key) {
+            self.buttons.set_bit(button as u8, pressed);
+        }
+    }
+    fn set_axis(&mut self, axis: AbsoluteAxisType, value: i32) {
+        match axis {
+            AbsoluteAxisType::ABS_HAT0X => {
+                self.hat =
+                    self.hat & !0x30 | HAT_THRESHOLDS.directions(value, Button::Left, Button::Right)
+            }
+            AbsoluteAxisType::ABS_HAT0Y => {
+                self.hat =
+                    self.hat & !0xC0 | HAT_THRESHOLDS.directions(value, Button::Up, Button::Down)
+            }
+            AbsoluteAxisType::ABS_X => {
+                self.stick =
+                    self.stick & !0x30 | self.stick_x.directions(value, Button::Left, Button::Right)
+            }
+            AbsoluteAxisType::ABS_Y => {
+                self.stick =
+                    self.stick & !0xC0 | self.stick_y.directions(value, Button::Up, Button::Down)
+            }
+            _ => {}
+        }
+    }
+    fn pressed(&self) -> u8 {
+        self.buttons | self.hat | self.stick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev::{AbsoluteAxisType, Key};
+
+    use super::{AxisThresholds, GamepadState};
+    use crate::joypad::Button;
+
+    #[test]
+    fn axis_thresholds_test() {
+        let thresholds = AxisThresholds::new(0, 255);
+        assert_eq!(thresholds, AxisThresholds { low: 64, high: 190 });
+        assert_eq!(thresholds.directions(127, Button::Left, Button::Right), 0);
+        assert_eq!(thresholds.directions(10, Button::Left, Button::Right), 0x20);
+        assert_eq!(thresholds.directions(250, Button::Up, Button::Down), 0x80);
+    }
+    #[test]
+    fn gamepad_state_test() {
+        let thresholds = AxisThresholds::new(-32768, 32767);
+        let mut state = GamepadState::with_thresholds(thresholds, thresholds);
+        state.set_key(Key::BTN_EAST, true);
+        state.set_key(Key::BTN_START, true);
+        assert_eq!(state.pressed(), 0x09);
+        // Hat and stick holding left, centering the stick keeps the hat direction
+        state.set_axis(AbsoluteAxisType::ABS_HAT0X, -1);
+        state.set_axis(AbsoluteAxisType::ABS_X, -30000);
+        state.set_axis(AbsoluteAxisType::ABS_Y, 30000);
+        assert_eq!(state.pressed(), 0xA9);
+        state.set_axis(AbsoluteAxisType::ABS_X, 0);
+        state.set_axis(AbsoluteAxisType::ABS_Y, 100);
+        assert_eq!(state.pressed(), 0x29);
+        state.set_axis(AbsoluteAxisType::ABS_HAT0X, 0);
+        state.set_key(Key::BTN_EAST, false);
+        assert_eq!(state.pressed(), 0x08);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{error_type::Errors, u8_traits::Bit},
+};
+
+/// Buttons of the console, the value is the bit in the pressed state
+/// action buttons in the low nibble, directions in the high nibble
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Right = 4,
+    Left = 5,
+    Up = 6,
+    Down = 7,
+}
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+    ];
+}
+
+/// P1/JOYP register (0xFF00), the game selects the directions (bit 4) and/or
+/// the action buttons (bit 5) by writing 0 and reads pressed buttons as 0 in bits 0-3
+pub struct Joypad {
+    pressed: u8,
+    select: u8,
+}
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: 0,
+            select: 0x30,
+        }
+    }
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+    // Input lines, 0 when a selected button is pressed
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if !self.select.get_bit(4) {
+            pressed |= self.pressed >> 4;
+        }
+        if !self.select.get_bit(5) {
+            pressed |= self.pressed & 0x0F;
+        }
+        !pressed & 0x0F
+    }
+    /// Return true if the joypad interupt should be requested
+    pub fn write(&mut self, value: u8) -> bool {
+        let old_lines = self.lines();
+        self.select = value & 0x30;
+        Joypad::falling_edge(old_lines, self.lines())
+    }
+    /// Return true if the joypad interupt should be requested
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let old_lines = self.lines();
+        self.pressed.set_bit(button as u8, pressed);
+        Joypad::falling_edge(old_lines, self.lines())
+    }
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed.get_bit(button as u8)
+    }
+    // The interupt is requested when any line goes from high to low
+    fn falling_edge(old_lines: u8, new_lines: u8) -> bool {
+        old_lines & !new_lines != 0
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.pressed);
+        writer.write_u8(self.select);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.pressed = reader.read_u8()?;
+        self.select = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Button, Joypad};
+
+    #[test]
+    fn select_test() {
+        let mut joypad = Joypad::new();
+        assert_eq!(joypad.read(), 0xFF);
+        joypad.set_button(Button::Start, true);
+        joypad.set_button(Button::Left, true);
+        // Nothing selected
+        assert_eq!(joypad.read(), 0xFF);
+        // Directions
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xED);
+        // Action buttons
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD7);
+        // Both
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC5);
+        joypad.set_button(Button::Start, false);
+        assert!(!joypad.is_pressed(Button::Start));
+        assert_eq!(joypad.read(), 0xCD);
+    }
+    #[test]
+    fn interupt_test() {
+        let mut joypad = Joypad::new();
+        // Not selected, no interupt
+        assert!(!joypad.set_button(Button::A, true));
+        // Selecting the line of a pressed button is a high to low transition
+        assert!(joypad.write(0x10));
+        assert!(!joypad.set_button(Button::A, false));
+        assert!(joypad.set_button(Button::B, true));
+        assert!(!joypad.set_button(Button::Down, true));
+    }
+}
@@ -1,11 +1,19 @@
+pub mod apu;
 pub mod bus;
 pub mod cartridge;
+pub mod config;
 pub mod cpu;
 pub mod emulator;
+pub mod frontend;
+pub mod gbs;
 pub mod io_handler;
+pub mod joypad;
+pub mod model;
 pub mod ppu;
 pub mod quartz;
 pub mod register;
+pub mod rewind;
+pub mod save_state;
 pub mod timer_reg;
 pub mod util;
 pub mod windows;
@@ -56,7 +56,6 @@ impl VRAM {
         let tile_id = bus.read_byte_as_cpu(tile_id_address) as u16;
         // println!("tile id: {}, line: {}", tile_id, line);
         
-       println!("lcdc 4 {}", self.get_lcd_control().bg_win_tiles());
         // Convert tile id to tile address
         let tile_address = match self.get_lcd_control().bg_win_tiles() {
             true => 0x8000u16 + tile_id * 16,
@@ -108,7 +107,7 @@ impl VRAM {
         let mut oam_vec = vec![];
         let oam_mem_start = 0xFE00;
         let bus = self.bus.borrow();
-        for i in 0x00..0x40 {
+        for i in 0..40 {
             oam_vec.push(OAMSprite {
                 y: bus.read_byte(oam_mem_start + i * 4),
                 x: bus.read_byte(oam_mem_start + i * 4 + 1),
@@ -0,0 +1,162 @@
+use crate::register::Registers;
+
+/// Hardware revision emulated, each boot rom leaves the hardware in a slightly different state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+pub enum GameBoyModel {
+    DMG0,
+    DMG,
+    MGB,
+    SGB,
+    CGB,
+}
+impl std::str::FromStr for GameBoyModel {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(GameBoyModel::DMG0),
+            "dmg" => Ok(GameBoyModel::DMG),
+            "mgb" => Ok(GameBoyModel::MGB),
+            "sgb" => Ok(GameBoyModel::SGB),
+            "cgb" => Ok(GameBoyModel::CGB),
+            _ => Err(format!("unknown model {}, expected dmg0, dmg, mgb, sgb or cgb", name)),
+        }
+    }
+}
+impl GameBoyModel {
+    pub const ALL: [GameBoyModel; 5] = [
+        GameBoyModel::DMG0,
+        GameBoyModel::DMG,
+        GameBoyModel::MGB,
+        GameBoyModel::SGB,
+        GameBoyModel::CGB,
+    ];
+    /// Cpu registers when the boot rom jumps to 0x0100
+    /// the DMG boot rom set the H and C flags if the header checksum is not 0
+    pub fn post_boot_registers(&self, header_checksum: u8) -> Registers {
+        let (af, bc, de, hl) = match self {
+            GameBoyModel::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            GameBoyModel::DMG => (0x0180, 0x0013, 0x00D8, 0x014D),
+            GameBoyModel::MGB => (0xFF80, 0x0013, 0x00D8, 0x014D),
+            GameBoyModel::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+            // Values for a game using the CGB features
+            GameBoyModel::CGB => (0x1180, 0x0000, 0xFF56, 0x000D),
+        };
+        let mut reg = Registers::new();
+        reg.set_af(af);
+        reg.bc = bc;
+        reg.de = de;
+        reg.hl = hl;
+        reg.sp = 0xFFFE;
+        reg.pc = 0x0100;
+        if matches!(self, GameBoyModel::DMG | GameBoyModel::MGB) && header_checksum != 0 {
+            reg.set_flag_h(true);
+            reg.set_flag_c(true);
+        }
+        reg
+    }
+    /// Internal 16 bits counter of the timer, DIV being its upper byte
+    /// SGB and CGB boot time depends on the cartridge so those are approximations
+    pub fn post_boot_div_counter(&self) -> u16 {
+        match self {
+            GameBoyModel::DMG0 => 0x182C,
+            GameBoyModel::DMG | GameBoyModel::MGB => 0xABCC,
+            GameBoyModel::SGB => 0xD85C,
+            GameBoyModel::CGB => 0x267C,
+        }
+    }
+    /// Line and dot of the line the ppu is at when the boot rom ends, both in vblank
+    pub fn post_boot_ppu_position(&self) -> (u8, usize) {
+        match self {
+            GameBoyModel::DMG0 => (145, 60),
+            _ => (153, 400),
+        }
+    }
+    /// Value of the IO registers 0xFF00-0xFF7F when the boot rom ends (DIV excluded)
+    pub fn post_boot_io_registers(&self) -> Vec<(u16, u8)> {
+        let mut io = vec![
+            // Joypad, serial, timer and interupt flag
+            (0xFF00, 0xCF),
+            (0xFF01, 0x00),
+            (0xFF02, 0x7E),
+            (0xFF05, 0x00),
+            (0xFF06, 0x00),
+            (0xFF07, 0xF8),
+            (0xFF0F, 0xE1),
+            // Sound, powered on first as its registers are read only while it's off
+            (0xFF26, 0xF1),
+            (0xFF10, 0x80),
+            (0xFF11, 0xBF),
+            (0xFF12, 0xF3),
+            (0xFF13, 0xFF),
+            (0xFF14, 0xBF),
+            (0xFF16, 0x3F),
+            (0xFF17, 0x00),
+            (0xFF18, 0xFF),
+            (0xFF19, 0xBF),
+            (0xFF1A, 0x7F),
+            (0xFF1B, 0xFF),
+            (0xFF1C, 0x9F),
+            (0xFF1D, 0xFF),
+            (0xFF1E, 0xBF),
+            (0xFF20, 0xFF),
+            (0xFF21, 0x00),
+            (0xFF22, 0x00),
+            (0xFF23, 0xBF),
+            (0xFF24, 0x77),
+            (0xFF25, 0xF3),
+            // LCD
+            (0xFF40, 0x91),
+            (0xFF41, 0x85),
+            (0xFF42, 0x00),
+            (0xFF43, 0x00),
+            (0xFF44, 0x00),
+            (0xFF45, 0x00),
+            (0xFF46, 0xFF),
+            (0xFF47, 0xFC),
+            (0xFF48, 0xFF),
+            (0xFF49, 0xFF),
+            (0xFF4A, 0x00),
+            (0xFF4B, 0x00),
+            // Boot rom is unmapped
+            (0xFF50, 0xFF),
+        ];
+        let overrides: &[(u16, u8)] = match self {
+            GameBoyModel::DMG0 => &[(0xFF41, 0x81), (0xFF44, 0x91)],
+            GameBoyModel::DMG | GameBoyModel::MGB => &[],
+            GameBoyModel::SGB => &[(0xFF26, 0xF0)],
+            GameBoyModel::CGB => &[(0xFF02, 0x7F), (0xFF46, 0x00)],
+        };
+        for (address, value) in overrides {
+            if let Some(register) = io.iter_mut().find(|(a, _)| a == address) {
+                register.1 = *value;
+            }
+        }
+        io
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GameBoyModel;
+
+    #[test]
+    fn post_boot_registers_test() {
+        let reg = GameBoyModel::DMG.post_boot_registers(0x4D);
+        assert_eq!(reg.get_af(), 0x01B0);
+        assert_eq!(reg.hl, 0x014D);
+        assert_eq!(reg.pc, 0x0100);
+        let reg = GameBoyModel::DMG.post_boot_registers(0x00);
+        assert_eq!(reg.get_af(), 0x0180);
+        let reg = GameBoyModel::CGB.post_boot_registers(0x4D);
+        assert_eq!(reg.get_af(), 0x1180);
+    }
+    #[test]
+    fn post_boot_io_test() {
+        let io = GameBoyModel::SGB.post_boot_io_registers();
+        assert!(io.contains(&(0xFF26, 0xF0)));
+        assert!(io.contains(&(0xFF40, 0x91)));
+        let io = GameBoyModel::DMG0.post_boot_io_registers();
+        assert!(io.contains(&(0xFF44, 0x91)));
+        assert_eq!(io.len(), GameBoyModel::DMG.post_boot_io_registers().len());
+    }
+}
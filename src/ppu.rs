@@ -3,7 +3,12 @@ use std::{cell::RefCell, rc::Rc};
 use crate::{
     bus::{Bus, LCDControlReg, LCDStatusReg},
     mem::vram::VRAM,
-    util::tiles_util::{tile_fuse_byte_u8, ScreenVector},
+    model::GameBoyModel,
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{
+        error_type::Errors,
+        tiles_util::{tile_fuse_byte_u8, ScreenVector},
+    },
     windows::game_window::{GAMEBOY_SCREEN_HEIGHT, GAMEBOY_SCREEN_WIDTH},
 };
 
@@ -67,6 +72,15 @@ impl PPU {
             mode_3_pixel_counter: 0,
         }
     }
+    // Put the ppu in vblank where the boot rom of the model leaves it
+    pub fn set_post_boot_position(&mut self, model: GameBoyModel) {
+        let (ly, dot) = model.post_boot_ppu_position();
+        self.current_mode = PPUModes::Mode1;
+        self.ly = ly;
+        self.dots_counter_line = dot;
+        self.dots_counter_mode = (ly as usize - 144) * 456 + dot;
+        self.dots_counter_frame = ly as usize * 456 + dot;
+    }
     pub fn next_tick(&mut self) {
         // 4 dots per cpu cycle so one per clock cycle
         self.dots_counter_frame += 1;
@@ -183,15 +197,7 @@ impl PPU {
 
             // obj
             for obj in line_object {
-                println!(
-                    "obj.y: {}, ly_screen: {}, height: {}",
-                    obj.y, ly_screen, obj_height
-                );
                 let y_obj_offset = ly_screen - obj.y;
-                println!(
-                    "offset: {}, obj.y: {}, ly_screen: {}, height: {}",
-                    y_obj_offset, obj.y, ly_screen, obj_height
-                );
                 let (l, h) =
                     self.vram
                         .get_tile_line(y_obj_offset, obj.tile_number, obj_height == 16);
@@ -287,6 +293,61 @@ impl PPU {
 
     fn draw() {}
 }
+impl SaveState for PPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(match self.current_mode {
+            PPUModes::Mode0 => 0,
+            PPUModes::Mode1 => 1,
+            PPUModes::Mode2 => 2,
+            PPUModes::Mode3 => 3,
+        });
+        writer.write_u64(self.dots_counter_frame as u64);
+        writer.write_u64(self.dots_counter_line as u64);
+        writer.write_u64(self.dots_counter_mode as u64);
+        writer.write_bytes(&self.line_pixels);
+        writer.write_u64(self.mode_3_last_dots_counter as u64);
+        writer.write_u64(self.mode_3_pixel_counter as u64);
+        writer.write_u8(self.ly);
+        writer.write_u32(self.screen_array.pixelcolor_vec.len() as u32);
+        for &pixel in &self.screen_array.pixelcolor_vec {
+            writer.write_u32(pixel);
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.current_mode = match reader.read_u8()? {
+            0 => PPUModes::Mode0,
+            1 => PPUModes::Mode1,
+            2 => PPUModes::Mode2,
+            3 => PPUModes::Mode3,
+            mode => {
+                return Err(Errors::InvalidSaveState(format!(
+                    "invalid ppu mode {}",
+                    mode
+                )))
+            }
+        };
+        self.dots_counter_frame = reader.read_u64()? as usize;
+        self.dots_counter_line = reader.read_u64()? as usize;
+        self.dots_counter_mode = reader.read_u64()? as usize;
+        reader.read_bytes_into(&mut self.line_pixels)?;
+        self.mode_3_last_dots_counter = reader.read_u64()? as usize;
+        self.mode_3_pixel_counter = reader.read_u64()? as usize;
+        self.ly = reader.read_u8()?;
+        let pixel_count = reader.read_u32()? as usize;
+        if pixel_count != self.screen_array.pixelcolor_vec.len() {
+            return Err(Errors::InvalidSaveState(format!(
+                "screen of {} pixels instead of {}",
+                pixel_count,
+                self.screen_array.pixelcolor_vec.len()
+            )));
+        }
+        for pixel in self.screen_array.pixelcolor_vec.iter_mut() {
+            *pixel = reader.read_u32()?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
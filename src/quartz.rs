@@ -3,6 +3,9 @@ use std::{
     time::{Duration, Instant},
 };
 
+/// Console clock, in ticks per second
+pub const CLOCK_FREQUENCY: u32 = 4_194_304;
+
 pub struct Quartz {
     frequency: u32,
     delta_step: u128,
@@ -14,6 +17,13 @@ impl Quartz {
         // let frequency = 4194304u32;
         // Faster value
         let frequency = 600000000u32;
+        Quartz::with_frequency(frequency)
+    }
+    /// Run at a multiple of the console clock speed
+    pub fn with_speed(speed: f32) -> Quartz {
+        Quartz::with_frequency((CLOCK_FREQUENCY as f32 * speed) as u32)
+    }
+    pub fn with_frequency(frequency: u32) -> Quartz {
         Quartz {
             frequency,
             delta_step: Quartz::step_length_nanosec(frequency),
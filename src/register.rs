@@ -1,6 +1,9 @@
 use std::u16::MAX as MAXu16;
 
-use crate::util::opcode_dict_util::NopreOperands;
+use crate::{
+    save_state::{SaveState, StateReader, StateWriter},
+    util::{error_type::Errors, opcode_dict_util::NopreOperands},
+};
 
 struct Register(u16);
 pub struct Registers {
@@ -207,6 +210,23 @@ fn get_high(bytes: &u16) -> u8 {
     return (bytes >> 8 & 0xFF) as u8;
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for value in [self.af, self.bc, self.de, self.hl, self.sp, self.pc] {
+            writer.write_u16(value);
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors> {
+        self.set_af(reader.read_u16()?);
+        self.bc = reader.read_u16()?;
+        self.de = reader.read_u16()?;
+        self.hl = reader.read_u16()?;
+        self.sp = reader.read_u16()?;
+        self.pc = reader.read_u16()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::register::{get_bit, set_bit, set_high, set_low};
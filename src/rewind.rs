@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+// Longest run of unchanged or changed bytes of a delta record
+const MAX_RUN: usize = u16::MAX as usize;
+
+/// Save states taken every `interval` frames, bounded by a memory limit in bytes.
+/// The newest state is kept whole and each older one as its xor with the next one,
+/// run length encoded so the bytes that didn't change take no space
+pub struct RewindBuffer {
+    interval: u64,
+    memory_limit: usize,
+    // (frame, state)
+    newest: Option<(u64, Vec<u8>)>,
+    // (frame, delta against the next snapshot), oldest first
+    older: VecDeque<(u64, Vec<u8>)>,
+    memory_usage: usize,
+}
+impl RewindBuffer {
+    pub fn new(interval: u64, memory_limit: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            memory_limit,
+            newest: None,
+            older: VecDeque::new(),
+            memory_usage: 0,
+        }
+    }
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+    /// The oldest snapshots are dropped past the memory limit, the newest is always kept
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        if let Some((newest_frame, newest_state)) = self.newest.take() {
+            let delta = encode_delta(&state, &newest_state);
+            self.memory_usage = self.memory_usage - newest_state.len() + delta.len();
+            self.older.push_back((newest_frame, delta));
+        }
+        self.memory_usage += state.len();
+        self.newest = Some((frame, state));
+        while self.memory_usage > self.memory_limit {
+            match self.older.pop_front() {
+                Some((_, delta)) => self.memory_usage -= delta.len(),
+                None => break,
+            }
+        }
+    }
+    pub fn newest(&self) -> Option<(u64, &[u8])> {
+        self.newest
+            .as_ref()
+            .map(|(frame, state)| (*frame, state.as_slice()))
+    }
+    /// Remove the newest snapshot, the one before it becomes the newest
+    pub fn pop(&mut self) -> Option<(u64, Vec<u8>)> {
+        let (frame, state) = self.newest.take()?;
+        self.memory_usage -= state.len();
+        if let Some((older_frame, delta)) = self.older.pop_back() {
+            let older_state = apply_delta(&state, &delta);
+            self.memory_usage = self.memory_usage - delta.len() + older_state.len();
+            self.newest = Some((older_frame, older_state));
+        }
+        Some((frame, state))
+    }
+    pub fn len(&self) -> usize {
+        self.older.len() + self.newest.is_some() as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+    /// Bytes taken by the snapshots
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+        self.memory_usage = 0;
+    }
+}
+
+// Length of the target then records of (unchanged count u16, changed count u16,
+// changed bytes xored with the base), the base is padded with 0 to the target length
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(target.len() as u32).to_le_bytes());
+    let mut i = 0;
+    while i < target.len() {
+        let unchanged_start = i;
+        while i < target.len() && i - unchanged_start < MAX_RUN && xor(i) == 0 {
+            i += 1;
+        }
+        let changed_start = i;
+        while i < target.len() && i - changed_start < MAX_RUN && xor(i) != 0 {
+            i += 1;
+        }
+        delta.extend_from_slice(&((changed_start - unchanged_start) as u16).to_le_bytes());
+        delta.extend_from_slice(&((i - changed_start) as u16).to_le_bytes());
+        delta.extend((changed_start..i).map(xor));
+    }
+    delta
+}
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let length = u32::from_le_bytes(delta[0..4].try_into().unwrap()) as usize;
+    let mut target: Vec<u8> = (0..length)
+        .map(|i| base.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut position = 0;
+    let mut i = 4;
+    while i < delta.len() {
+        let unchanged = u16::from_le_bytes([delta[i], delta[i + 1]]) as usize;
+        let changed = u16::from_le_bytes([delta[i + 2], delta[i + 3]]) as usize;
+        i += 4;
+        position += unchanged;
+        for (byte, xor) in target[position..position + changed]
+            .iter_mut()
+            .zip(&delta[i..i + changed])
+        {
+            *byte ^= xor;
+        }
+        position += changed;
+        i += changed;
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, encode_delta, RewindBuffer};
+
+    #[test]
+    fn delta_test() {
+        let base: Vec<u8> = (0..=255).cycle().take(0x20000).collect();
+        let mut target = base.clone();
+        target[10] = 0;
+        target[0x1_8000..0x1_9000].fill(0xAA);
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 0x1100);
+        assert_eq!(apply_delta(&base, &delta), target);
+        assert_eq!(apply_delta(&base, &encode_delta(&base, &base)), base);
+        // States can change size, when the boot rom is unmapped
+        assert_eq!(
+            apply_delta(&base, &encode_delta(&base, &target[..100])),
+            &target[..100]
+        );
+        assert_eq!(
+            apply_delta(&target[..100], &encode_delta(&target[..100], &base)),
+            base
+        );
+    }
+    #[test]
+    fn buffer_test() {
+        let state = |frame: u8| {
+            let mut state = vec![0u8; 1000];
+            state[frame as usize] = frame;
+            state
+        };
+        let mut buffer = RewindBuffer::new(2, 1100);
+        for frame in [2, 4, 6, 8] {
+            buffer.push(frame, state(frame as u8));
+        }
+        assert_eq!(buffer.len(), 4);
+        assert!(buffer.memory_usage() <= 1100);
+        assert_eq!(buffer.pop(), Some((8, state(8))));
+        assert_eq!(buffer.newest(), Some((6, state(6).as_slice())));
+        assert_eq!(buffer.pop(), Some((6, state(6))));
+        assert_eq!(buffer.pop(), Some((4, state(4))));
+        assert_eq!(buffer.pop(), Some((2, state(2))));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.memory_usage(), 0);
+
+        // Past the limit only the newest states are kept
+        let mut buffer = RewindBuffer::new(1, 1000);
+        for frame in 0..10 {
+            buffer.push(frame, vec![frame as u8; 1000]);
+        }
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.newest(), Some((9, [9u8; 1000].as_slice())));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::util::{error_type::Errors, patch_util::crc32};
+
+const SAVE_STATE_MAGIC: &[u8; 4] = b"GBST";
+/// Bumped whenever the saved fields change, older states are rejected
+pub const SAVE_STATE_VERSION: u16 = 1;
+// Magic, version, global and header checksums of the rom, payload size and crc32
+const SAVE_STATE_HEADER_SIZE: usize = 4 + 2 + 2 + 1 + 4 + 4;
+
+/// Component whose whole state can be written to and restored from a save state
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Errors>;
+}
+
+/// Little endian binary encoding of the state of the components
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+    /// Length prefixed bytes
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Errors> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .ok_or_else(|| Errors::InvalidSaveState("truncated save state".to_string()))?;
+        self.position += length;
+        Ok(bytes)
+    }
+    pub fn read_u8(&mut self) -> Result<u8, Errors> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn read_bool(&mut self) -> Result<bool, Errors> {
+        Ok(self.read_u8()? != 0)
+    }
+    pub fn read_u16(&mut self) -> Result<u16, Errors> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn read_u32(&mut self) -> Result<u32, Errors> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, Errors> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn read_f32(&mut self) -> Result<f32, Errors> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], Errors> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+    /// Length prefixed bytes into a buffer that must have the same length
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), Errors> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(Errors::InvalidSaveState(format!(
+                "expected {} bytes, got {}",
+                buffer.len(),
+                bytes.len()
+            )));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+/// Identify the rom a state was saved with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomChecksum {
+    pub global_checksum: u16,
+    pub header_checksum: u8,
+}
+
+/// Header followed by the payload, the payload crc32 is checked before anything is restored
+pub fn encode_save_state(rom: RomChecksum, payload: &[u8]) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    writer.data.extend_from_slice(SAVE_STATE_MAGIC);
+    writer.write_u16(SAVE_STATE_VERSION);
+    writer.write_u16(rom.global_checksum);
+    writer.write_u8(rom.header_checksum);
+    writer.write_u32(payload.len() as u32);
+    writer.write_u32(crc32(payload));
+    writer.data.extend_from_slice(payload);
+    writer.into_bytes()
+}
+/// Payload of the state if it was saved with the same format version and rom
+pub fn decode_save_state(data: &[u8], rom: RomChecksum) -> Result<&[u8], Errors> {
+    if data.len() < SAVE_STATE_HEADER_SIZE || &data[0..4] != SAVE_STATE_MAGIC {
+        return Err(Errors::InvalidSaveState("not a save state".to_string()));
+    }
+    let mut reader = StateReader::new(&data[4..SAVE_STATE_HEADER_SIZE]);
+    let version = reader.read_u16()?;
+    if version != SAVE_STATE_VERSION {
+        return Err(Errors::InvalidSaveState(format!(
+            "format version {} is not supported, expected {}",
+            version, SAVE_STATE_VERSION
+        )));
+    }
+    let state_rom = RomChecksum {
+        global_checksum: reader.read_u16()?,
+        header_checksum: reader.read_u8()?,
+    };
+    if state_rom != rom {
+        return Err(Errors::InvalidSaveState(format!(
+            "saved with another rom (checksum {:04X}/{:02X}, current rom {:04X}/{:02X})",
+            state_rom.global_checksum,
+            state_rom.header_checksum,
+            rom.global_checksum,
+            rom.header_checksum
+        )));
+    }
+    let size = reader.read_u32()? as usize;
+    let checksum = reader.read_u32()?;
+    let payload = &data[SAVE_STATE_HEADER_SIZE..];
+    if payload.len() != size || crc32(payload) != checksum {
+        return Err(Errors::InvalidSaveState("corrupted save state".to_string()));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_save_state, encode_save_state, RomChecksum, StateReader, StateWriter,
+        SAVE_STATE_VERSION,
+    };
+
+    const ROM: RomChecksum = RomChecksum {
+        global_checksum: 0x1234,
+        header_checksum: 0x56,
+    };
+
+    #[test]
+    fn writer_reader_test() {
+        let mut writer = StateWriter::new();
+        writer.write_bool(true);
+        writer.write_u16(0xBEEF);
+        writer.write_u64(1 << 40);
+        writer.write_f32(-0.5);
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+        let mut reader = StateReader::new(&data);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0xBEEF);
+        assert_eq!(reader.read_u64().unwrap(), 1 << 40);
+        assert_eq!(reader.read_f32().unwrap(), -0.5);
+        let mut buffer = [0u8; 2];
+        assert!(reader.read_bytes_into(&mut buffer).is_err());
+        assert!(reader.is_empty());
+        assert!(reader.read_u8().is_err());
+    }
+    #[test]
+    fn header_test() {
+        let state = encode_save_state(ROM, &[1, 2, 3, 4]);
+        assert_eq!(decode_save_state(&state, ROM).unwrap(), &[1, 2, 3, 4]);
+        let other_rom = RomChecksum {
+            global_checksum: 0x1235,
+            ..ROM
+        };
+        assert!(decode_save_state(&state, other_rom).is_err());
+        let mut corrupted = state.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(decode_save_state(&corrupted, ROM).is_err());
+        let mut newer = state.clone();
+        newer[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(decode_save_state(&newer, ROM).is_err());
+        assert!(decode_save_state(&state[..state.len() - 1], ROM).is_err());
+    }
+}
@@ -1,9 +1,33 @@
-use crate::{
-    bus::Bus,
-    util::u8_traits::{Bit, NibblesU16, NibblesU8},
-};
+use crate::{bus::Bus, util::u8_traits::NibblesU16};
 use std::{cell::RefCell, rc::Rc};
 
+// Clock ticks between TIMA overflowing and being reloaded from TMA
+pub const TIMA_RELOAD_DELAY: u8 = 4;
+
+/// Where TIMA is in its overflow sequence, the count is the ticks left in that state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimaState {
+    Counting,
+    // TIMA reads 0, writing to it cancels the reload and the interupt
+    Overflowed(u8),
+    // TIMA was just reloaded, writes to TIMA are ignored and writes to TMA are copied to TIMA
+    Reloaded(u8),
+}
+
+/// Bit of the internal DIV counter TIMA is clocked from for each TAC clock select
+pub fn tima_div_bit(tac: u8) -> u8 {
+    match tac & 0x03 {
+        0 => 9,
+        1 => 3,
+        2 => 5,
+        _ => 7,
+    }
+}
+/// Input of the falling edge detector incrementing TIMA, selected DIV bit AND timer enable
+pub fn timer_input(div_counter: u16, tac: u8) -> bool {
+    tac & 0x04 != 0 && (div_counter >> tima_div_bit(tac)) & 1 == 1
+}
+
 pub struct TimerReg {
     bus: Rc<RefCell<Bus>>,
 }
@@ -12,67 +36,27 @@ impl TimerReg {
         TimerReg { bus }
     }
     pub fn next_tick(&mut self) {
-        {
-            let mut bus = self.bus.borrow_mut();
-            bus.timer_div_intern = bus.timer_div_intern.wrapping_add(1);
-        }
-        self.div_tick();
-        self.tima_tick();
-    }
-    // TODO: refactor
-    // TODO: implement Timer Overflow Behaviour with proper delay
-    fn tima_tick(&mut self) {
-        // If tima is enable
-        if self.get_tac_ff07().get_bit(2) {
-            let tima_rate = self.get_tima_rate();
-            let mut bus = self.bus.borrow_mut();
-            bus.timer_tima_intern = bus.timer_tima_intern.wrapping_add(1);
-            // println!(
-            //     "tima_rate {}, tima_intern {}",
-            //     tima_rate, bus.timer_tima_intern
-            // );
-
-            if bus.timer_tima_intern >= tima_rate {
-                let mut tima = bus.read_byte(0xFF05);
-                if tima != 0 {
-                    tima = tima + 1;
-                }
-
-                // When overflow reset to value of ff06
-                let tima = match tima {
-                    255 => 0,
-                    0 => {
-                        // Setting flag
-                        bus.write_bit(0xff0f, 2, true);
-                        let a = bus.read_byte(0xff06);
-                        if a == 0 {
-                            1
-                        } else {
-                            a
-                        }
-                    }
-                    _ => tima,
-                };
-                bus.write_byte(0xff05, tima);
+        let mut bus = self.bus.borrow_mut();
+        let old_input = bus.timer_input();
+        bus.timer_div_intern = bus.timer_div_intern.wrapping_add(1);
+        let div = bus.timer_div_intern.high_8nibble();
+        bus.write_byte(0xFF04, div);
 
-                bus.timer_tima_intern -= tima_rate
+        bus.timer_tima_state = match bus.timer_tima_state {
+            TimaState::Overflowed(1) => {
+                let tma = bus.read_byte(0xFF06);
+                bus.write_byte(0xFF05, tma);
+                bus.write_bit(0xFF0F, 2, true);
+                TimaState::Reloaded(TIMA_RELOAD_DELAY)
             }
-        }
-    }
-    fn div_tick(&mut self) {
-        let timer_inter = self.bus.borrow().timer_div_intern;
-        self.bus
-            .borrow_mut()
-            .write_byte(0xff04, timer_inter.high_8nibble());
-    }
-    fn get_tima_rate(&self) -> u16 {
-        let byte = self.get_tac_ff07();
-        let bits = (byte.get_bit(1), byte.get_bit(0));
-        match bits {
-            (true, true) => 256,
-            (true, false) => 64,
-            (false, true) => 16,
-            (false, false) => 1024,
+            TimaState::Overflowed(ticks) => TimaState::Overflowed(ticks - 1),
+            TimaState::Reloaded(1) => TimaState::Counting,
+            TimaState::Reloaded(ticks) => TimaState::Reloaded(ticks - 1),
+            TimaState::Counting => TimaState::Counting,
+        };
+
+        if old_input && !bus.timer_input() {
+            bus.increment_tima();
         }
     }
     // divider
@@ -92,3 +76,82 @@ impl TimerReg {
         self.bus.borrow().read_byte(0xFF07)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{TimaState, TimerReg, TIMA_RELOAD_DELAY};
+    use crate::bus::Bus;
+
+    // Timer enabled, incrementing every 16 ticks
+    fn fast_timer() -> (Rc<RefCell<Bus>>, TimerReg) {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow_mut().write_byte_as_cpu(0xFF07, 0x05);
+        let timer = TimerReg::new(Rc::clone(&bus));
+        (bus, timer)
+    }
+    fn run(timer: &mut TimerReg, ticks: usize) {
+        for _ in 0..ticks {
+            timer.next_tick();
+        }
+    }
+
+    #[test]
+    fn falling_edge_test() {
+        let (bus, mut timer) = fast_timer();
+        run(&mut timer, 15);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 0);
+        run(&mut timer, 1);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 1);
+        run(&mut timer, 16 * 4);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 5);
+        assert_eq!(bus.borrow().read_byte(0xFF04), 0);
+        run(&mut timer, 256 - 16 * 5);
+        assert_eq!(bus.borrow().read_byte(0xFF04), 1);
+    }
+    #[test]
+    fn overflow_reload_test() {
+        let (bus, mut timer) = fast_timer();
+        bus.borrow_mut().write_byte_as_cpu(0xFF05, 0xFF);
+        bus.borrow_mut().write_byte_as_cpu(0xFF06, 0x42);
+        run(&mut timer, 16);
+        // TIMA stays at 0 for 4 ticks before being reloaded
+        assert_eq!(bus.borrow().read_byte(0xFF05), 0x00);
+        assert_eq!(bus.borrow().read_byte(0xFF0F) & 0x04, 0x00);
+        run(&mut timer, TIMA_RELOAD_DELAY as usize);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 0x42);
+        assert_eq!(bus.borrow().read_byte(0xFF0F) & 0x04, 0x04);
+        // Writes to TMA right after the reload also go to TIMA, writes to TIMA are ignored
+        bus.borrow_mut().write_byte_as_cpu(0xFF06, 0x50);
+        bus.borrow_mut().write_byte_as_cpu(0xFF05, 0x10);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 0x50);
+    }
+    #[test]
+    fn tima_write_cancel_test() {
+        let (bus, mut timer) = fast_timer();
+        bus.borrow_mut().write_byte_as_cpu(0xFF05, 0xFF);
+        bus.borrow_mut().write_byte_as_cpu(0xFF06, 0x42);
+        run(&mut timer, 16);
+        assert_eq!(bus.borrow().timer_tima_state, TimaState::Overflowed(4));
+        bus.borrow_mut().write_byte_as_cpu(0xFF05, 0x10);
+        run(&mut timer, TIMA_RELOAD_DELAY as usize);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 0x10);
+        assert_eq!(bus.borrow().read_byte(0xFF0F) & 0x04, 0x00);
+    }
+    #[test]
+    fn div_and_tac_write_glitch_test() {
+        let (bus, mut timer) = fast_timer();
+        // DIV bit 3 set, resetting DIV is a falling edge
+        run(&mut timer, 8);
+        bus.borrow_mut().write_byte_as_cpu(0xFF04, 0x12);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 1);
+        assert_eq!(bus.borrow().timer_div_intern, 0);
+        // Disabling the timer while the selected bit is set is also a falling edge
+        run(&mut timer, 8);
+        bus.borrow_mut().write_byte_as_cpu(0xFF07, 0x01);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 2);
+        run(&mut timer, 64);
+        assert_eq!(bus.borrow().read_byte(0xFF05), 2);
+    }
+}
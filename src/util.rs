@@ -6,3 +6,7 @@ pub mod math_util;
 pub mod u8_traits;
 pub mod tiles_util;
 pub mod cartridge_util;
+pub mod licensee_util;
+pub mod patch_util;
+pub mod wav_util;
+pub mod archive_util;
@@ -0,0 +1,253 @@
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+
+use crate::util::{error_type::Errors, patch_util::crc32};
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4B50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4B50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4B50;
+const ZIP_END_OF_DIRECTORY_SIZE: usize = 22;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+const ROM_EXTENSIONS: [&str; 2] = [".gb", ".gbc"];
+/// 512 banks of an MBC5 cartridge, bigger archive content or patch target is rejected
+/// before it fills the memory
+pub const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+/// Rom inside a gzip or zip archive, recognized by their magic number,
+/// other data is returned as it is
+pub fn extract_rom(data: Vec<u8>) -> Result<Vec<u8>, Errors> {
+    if data.starts_with(&GZIP_MAGIC) {
+        return decompress(GzDecoder::new(data.as_slice()), 0)
+            .map_err(|e| Errors::InvalidArchive(format!("gzip: {}", e)));
+    }
+    if data.starts_with(&ZIP_LOCAL_HEADER.to_le_bytes()) {
+        return extract_zip_rom(&data);
+    }
+    Ok(data)
+}
+
+// At most MAX_ROM_SIZE bytes of the decoder output
+fn decompress(decoder: impl Read, size_hint: usize) -> Result<Vec<u8>, String> {
+    let mut rom = Vec::with_capacity(size_hint.min(MAX_ROM_SIZE));
+    decoder
+        .take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut rom)
+        .map_err(|e| e.to_string())?;
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(format!("more than {} bytes", MAX_ROM_SIZE));
+    }
+    Ok(rom)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, Errors> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| Errors::InvalidArchive("truncated zip".to_string()))
+}
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, Errors> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| Errors::InvalidArchive("truncated zip".to_string()))
+}
+
+/// Entry of the zip central directory
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    size: usize,
+    local_header_offset: usize,
+}
+
+// The central directory is found from the end of directory record, the last
+// record of the file followed by a comment of at most 0xFFFF bytes
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, Errors> {
+    let search_start = data
+        .len()
+        .saturating_sub(ZIP_END_OF_DIRECTORY_SIZE + 0xFFFF);
+    let end_offset = (search_start..=data.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE))
+        .rev()
+        .find(|&offset| u32_at(data, offset).is_ok_and(|sig| sig == ZIP_END_OF_DIRECTORY))
+        .ok_or_else(|| Errors::InvalidArchive("missing zip end of directory".to_string()))?;
+    let entry_count = u16_at(data, end_offset + 10)?;
+    let mut offset = u32_at(data, end_offset + 16)? as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        if u32_at(data, offset)? != ZIP_CENTRAL_HEADER {
+            return Err(Errors::InvalidArchive(
+                "corrupted zip directory".to_string(),
+            ));
+        }
+        let name_length = u16_at(data, offset + 28)? as usize;
+        let extra_length = u16_at(data, offset + 30)? as usize;
+        let comment_length = u16_at(data, offset + 32)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or_else(|| Errors::InvalidArchive("truncated zip".to_string()))?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(data, offset + 10)?,
+            crc32: u32_at(data, offset + 16)?,
+            compressed_size: u32_at(data, offset + 20)? as usize,
+            size: u32_at(data, offset + 24)? as usize,
+            local_header_offset: u32_at(data, offset + 42)? as usize,
+        });
+        offset += 46 + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+// First .gb or .gbc entry of the archive
+fn extract_zip_rom(data: &[u8]) -> Result<Vec<u8>, Errors> {
+    let entry = zip_entries(data)?
+        .into_iter()
+        .find(|entry| {
+            let name = entry.name.to_ascii_lowercase();
+            ROM_EXTENSIONS
+                .iter()
+                .any(|extension| name.ends_with(extension))
+        })
+        .ok_or_else(|| Errors::InvalidArchive("no .gb or .gbc file in the zip".to_string()))?;
+    if entry.size > MAX_ROM_SIZE {
+        return Err(Errors::InvalidArchive(format!(
+            "{}: {} bytes is too big for a rom",
+            entry.name, entry.size
+        )));
+    }
+
+    let offset = entry.local_header_offset;
+    if u32_at(data, offset)? != ZIP_LOCAL_HEADER {
+        return Err(Errors::InvalidArchive("corrupted zip entry".to_string()));
+    }
+    // The local header can have a different extra field than the central one
+    let data_offset =
+        offset + 30 + u16_at(data, offset + 26)? as usize + u16_at(data, offset + 28)? as usize;
+    let compressed = data
+        .get(data_offset..data_offset + entry.compressed_size)
+        .ok_or_else(|| Errors::InvalidArchive("truncated zip".to_string()))?;
+    let rom = match entry.method {
+        ZIP_STORED => compressed.to_vec(),
+        ZIP_DEFLATED => decompress(DeflateDecoder::new(compressed), entry.size)
+            .map_err(|e| Errors::InvalidArchive(format!("{}: {}", entry.name, e)))?,
+        method => {
+            return Err(Errors::InvalidArchive(format!(
+                "{}: unsupported compression method {}",
+                entry.name, method
+            )))
+        }
+    };
+    if rom.len() != entry.size || crc32(&rom) != entry.crc32 {
+        return Err(Errors::InvalidArchive(format!(
+            "{}: checksum mismatch",
+            entry.name
+        )));
+    }
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder},
+        Compression,
+    };
+
+    use super::{extract_rom, MAX_ROM_SIZE};
+    use crate::util::patch_util::crc32;
+
+    // Zip with the given (name, content, deflated) entries
+    fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, content, deflated) in entries {
+            let (method, compressed) = match deflated {
+                true => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(content).unwrap();
+                    (8u16, encoder.finish().unwrap())
+                }
+                false => (0u16, content.to_vec()),
+            };
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc32(content).to_le_bytes());
+            fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+
+            directory.extend_from_slice(&0x0201_4B50u32.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            data.extend_from_slice(&0x0403_4B50u32.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0]);
+            data.extend_from_slice(&fields);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&compressed);
+        }
+        let directory_offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&0x0605_4B50u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&directory_offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+        data
+    }
+
+    #[test]
+    fn raw_rom_test() {
+        assert_eq!(extract_rom(vec![1, 2, 3]).unwrap(), vec![1, 2, 3]);
+    }
+    #[test]
+    fn gzip_test() {
+        let rom: Vec<u8> = (0..=255).cycle().take(0x8000).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&rom).unwrap();
+        assert_eq!(extract_rom(encoder.finish().unwrap()).unwrap(), rom);
+    }
+    #[test]
+    fn zip_test() {
+        let rom: Vec<u8> = (0..=255).cycle().take(0x8000).collect();
+        let archive = zip(&[
+            ("readme.txt", b"not a rom", false),
+            ("Game.GB", &rom, true),
+            ("other.gbc", b"second rom", false),
+        ]);
+        assert_eq!(extract_rom(archive).unwrap(), rom);
+        let archive = zip(&[("game.gbc", &rom, false)]);
+        assert_eq!(extract_rom(archive).unwrap(), rom);
+        assert!(extract_rom(zip(&[("readme.txt", b"not a rom", true)])).is_err());
+        let mut corrupted = zip(&[("game.gb", &rom, false)]);
+        corrupted[100] ^= 0xFF;
+        assert!(extract_rom(corrupted).is_err());
+    }
+    #[test]
+    fn size_limit_test() {
+        let bomb = vec![0u8; MAX_ROM_SIZE + 1];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bomb).unwrap();
+        assert!(extract_rom(encoder.finish().unwrap()).is_err());
+        let archive = zip(&[("game.gb", &bomb, true)]);
+        assert!(extract_rom(archive).is_err());
+        // The size of the header is not trusted either
+        let mut lying = zip(&[("game.gb", &bomb, true)]);
+        let directory = lying.len() - 22 - 46 - "game.gb".len();
+        lying[directory + 24..directory + 28].copy_from_slice(&16u32.to_le_bytes());
+        assert!(extract_rom(lying).is_err());
+    }
+}
@@ -1,8 +1,14 @@
 use crate::util::{
-    display_impl::{u8_array_to_individual_char, u8_array_to_string},
+    archive_util::extract_rom,
     error_type::Errors,
+    licensee_util::licensee_name,
+    patch_util::{apply_patch, PatchFormat},
+};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
 };
-use std::{fs::File, io::Read};
 
 // start, offset
 
@@ -11,8 +17,14 @@ use std::{fs::File, io::Read};
 pub struct MemRange(pub usize, pub usize);
 pub struct CartridgeData(pub Vec<u8>);
 impl CartridgeData {
-    pub fn get_mbc_type(&self) -> MBCType {
-        self.0[CARTRIDGE_TYPE.0].into()
+    pub fn get_mbc_type(&self) -> Result<MBCType, Errors> {
+        self.0
+            .get(CARTRIDGE_TYPE.0)
+            .ok_or(Errors::TruncatedRom(self.0.len()))
+            .and_then(|&byte| byte.try_into())
+    }
+    pub fn get_header(&self) -> Result<CartridgeHeader, Errors> {
+        CartridgeHeader::parse(self)
     }
 }
 pub const NINTENDO_LOGO: MemRange = MemRange(0x0104, 0x0133);
@@ -31,6 +43,7 @@ pub const HEADER_CHECKSUM: MemRange = MemRange(0x014d, 0x014d);
 pub const GLOBAL_CHECKSUM: MemRange = MemRange(0x014e, 0x014f);
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MBCType {
     ROM_ONLY,
     MBC1,
@@ -61,9 +74,10 @@ pub enum MBCType {
     HuC3,
     HuC1_RAM_BATTERY,
 }
-impl From<u8> for MBCType {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for MBCType {
+    type Error = Errors;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
             0x00 => MBCType::ROM_ONLY,
             0x01 => MBCType::MBC1,
             0x02 => MBCType::MBC1_RAM,
@@ -92,84 +106,232 @@ impl From<u8> for MBCType {
             0xFD => MBCType::BANDAI_TAMA5,
             0xFE => MBCType::HuC3,
             0xFF => MBCType::HuC1_RAM_BATTERY,
-            _ => panic!("Not a valid mbc type"),
-        }
+            _ => return Err(Errors::InvalidMBCType(value)),
+        })
+    }
+}
+
+impl MBCType {
+    /// Cartridge with a battery keeping the external ram (and clock) powered
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            MBCType::MBC1_RAM_BATTERY
+                | MBCType::MBC2_BATTERY
+                | MBCType::ROM_RAM_BATTERY_1
+                | MBCType::MMM01_RAM_BATTERY
+                | MBCType::MBC3_TIMER_BATTERY
+                | MBCType::MBC3_TIMER_RAM_BATTERY_2
+                | MBCType::MBC3_RAM_BATTERY_2
+                | MBCType::MBC5_RAM_BATTERY
+                | MBCType::MBC5_RUMBLE_RAM_BATTERY
+                | MBCType::MBC7_SENSOR_RUMBLE_RAM_BATTERY
+                | MBCType::HuC1_RAM_BATTERY
+        )
     }
 }
 
-/// Load the rom into a Vec<u8>
+/// Load the rom into a Vec<u8>, extracting it if the file is a .zip or .gz archive
 pub fn load(file_path: &str) -> Result<CartridgeData, Errors> {
     let mut file = File::open(file_path)?;
     let mut file_data = Vec::new();
-    let byte_read = file.read_to_end(&mut file_data);
-    match byte_read {
-        Ok(x) => {
-            println!("loading file: {}", file_path);
-            eprintln!("Byte read: {}", x);
+    file.read_to_end(&mut file_data)
+        .map_err(Errors::ErrorReadingFile)?;
+    load_from_bytes(file_data)
+}
+/// Rom already in memory, as it is or in a .zip or .gz archive
+pub fn load_from_bytes(data: Vec<u8>) -> Result<CartridgeData, Errors> {
+    Ok(CartridgeData(extract_rom(data)?))
+}
+/// Load the rom and apply the first same-named .ips, .ups or .bps patch found
+/// next to it, the rom file itself is not modified
+pub fn load_patched(file_path: &str) -> Result<CartridgeData, Errors> {
+    let rom = load(file_path)?;
+    for format in PatchFormat::ALL {
+        let patch_path = Path::new(file_path).with_extension(format.extension());
+        if patch_path.exists() {
+            let patch = fs::read(&patch_path)?;
+            return Ok(CartridgeData(apply_patch(format, &rom.0, &patch)?));
         }
-        Err(e) => return Err(Errors::ErrorReadingFile(e)),
     }
-    return Ok(CartridgeData(file_data));
+    Ok(rom)
 }
-/// Calculate the header checksum and return true if it matched the rom_data
-pub fn check_checksum(rom_data: &Vec<u8>) -> bool {
-    let mut checksum = 0u8;
-    for address in 0x0134..=0x014C {
-        checksum = checksum.wrapping_sub(rom_data.get(address).unwrap() + 1);
-    }
-    let checksum_cart = &rom_data[HEADER_CHECKSUM.0 as usize..=HEADER_CHECKSUM.1 as usize]
-        .get(0)
-        .unwrap();
-    match checksum == **checksum_cart {
-        true => {
-            println!("Checksum header OK!");
-            return true;
+/// Logo checked by the boot rom, the game doesn't boot if it doesn't match
+pub const NINTENDO_LOGO_BYTES: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+// The header ends at 0x014F, any rom shorter than that can't be parsed
+const HEADER_END: usize = 0x0150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CGBSupport {
+    DMGOnly,
+    // Works on DMG but uses the CGB features when available
+    CGBEnhanced,
+    CGBOnly,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+/// Cartridge header found at 0x0100-0x014F
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_support: CGBSupport,
+    pub sgb_support: bool,
+    pub licensee: &'static str,
+    pub mbc_type: MBCType,
+    // Number of 16KiB rom banks
+    pub rom_banks: usize,
+    // External ram size in bytes
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub header_checksum_valid: bool,
+    pub global_checksum: u16,
+    pub global_checksum_valid: bool,
+    pub logo_valid: bool,
+}
+impl CartridgeHeader {
+    pub fn parse(data: &CartridgeData) -> Result<CartridgeHeader, Errors> {
+        let rom = &data.0;
+        if rom.len() < HEADER_END {
+            return Err(Errors::TruncatedRom(rom.len()));
         }
-        false => (),
+        let cgb_support = match rom[CGB_FLAG.0] {
+            0x80 => CGBSupport::CGBEnhanced,
+            0xC0 => CGBSupport::CGBOnly,
+            _ => CGBSupport::DMGOnly,
+        };
+        // On CGB games the end of the title area holds the manufacturer code and cgb flag
+        let title_end = match cgb_support {
+            CGBSupport::DMGOnly => TITLE.1,
+            _ => MANUFACTURER_CODE_OLD.0 - 1,
+        };
+        let title: String = rom[TITLE.0..=title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| char::from(byte))
+            .collect();
+        let rom_banks = match rom[ROM_SIZE.0] {
+            size @ 0x00..=0x08 => 2 << size,
+            0x52 => 72,
+            0x53 => 80,
+            0x54 => 96,
+            size => return Err(Errors::InvalidRomSize(size)),
+        };
+        let ram_size = match rom[RAM_SIZE.0] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x2_0000,
+            0x05 => 0x1_0000,
+            size => return Err(Errors::InvalidRamSize(size)),
+        };
+        let header_checksum = rom[HEADER_CHECKSUM.0];
+        let global_checksum = u16::from_be_bytes([rom[GLOBAL_CHECKSUM.0], rom[GLOBAL_CHECKSUM.1]]);
+        Ok(CartridgeHeader {
+            title: title.trim().to_string(),
+            cgb_support,
+            sgb_support: rom[SGB_FLAG.0] == 0x03,
+            licensee: licensee_name(
+                rom[OLD_LICENSEE_CODE.0],
+                [rom[NEW_LICENSEE_CODE.0], rom[NEW_LICENSEE_CODE.1]],
+            ),
+            mbc_type: data.get_mbc_type()?,
+            rom_banks,
+            ram_size,
+            destination: match rom[DESTINATION_CODE.0] {
+                0x00 => Destination::Japan,
+                _ => Destination::Overseas,
+            },
+            version: rom[ROM_VERSION_NUMBER.0],
+            header_checksum,
+            header_checksum_valid: header_checksum == compute_header_checksum(rom),
+            global_checksum,
+            global_checksum_valid: global_checksum == compute_global_checksum(rom),
+            logo_valid: rom[NINTENDO_LOGO.0..=NINTENDO_LOGO.1] == NINTENDO_LOGO_BYTES,
+        })
+    }
+}
+impl std::fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Licensee: {}", self.licensee)?;
+        writeln!(f, "Cartridge type: {:?}", self.mbc_type)?;
+        writeln!(
+            f,
+            "Rom size: {} banks ({} KiB)",
+            self.rom_banks,
+            self.rom_banks * 16
+        )?;
+        writeln!(f, "Ram size: {} KiB", self.ram_size / 1024)?;
+        writeln!(f, "CGB support: {:?}", self.cgb_support)?;
+        writeln!(f, "SGB support: {}", self.sgb_support)?;
+        writeln!(f, "Destination: {:?}", self.destination)?;
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Nintendo logo valid: {}", self.logo_valid)?;
+        writeln!(
+            f,
+            "Header checksum: {:#04x} valid: {}",
+            self.header_checksum, self.header_checksum_valid
+        )?;
+        write!(
+            f,
+            "Global checksum: {:#06x} valid: {}",
+            self.global_checksum, self.global_checksum_valid
+        )
     }
-    false
 }
-pub fn print_header(rom_data: &Vec<u8>) -> () {
-    let title = &rom_data[TITLE.0..=TITLE.1];
-    println!("##################");
-    println!("Title: {}", u8_array_to_individual_char(title));
-    let man_code = &rom_data[MANUFACTURER_CODE_OLD.0..=MANUFACTURER_CODE_OLD.1];
-    println!("Manufacturer code old: {}", u8_array_to_string(man_code));
-    let nintendo_logo = &rom_data[NINTENDO_LOGO.0..=NINTENDO_LOGO.1];
-    println!("Nintendo logo: {}", u8_array_to_string(nintendo_logo));
-    let cgb_flag = &rom_data[CGB_FLAG.0..=CGB_FLAG.1];
-    println!("CGB flag: {}", u8_array_to_string(cgb_flag));
-    let sgb_flag = &rom_data[SGB_FLAG.0..=SGB_FLAG.1];
-    println!("SGB flag: {}", u8_array_to_string(sgb_flag));
-    let new_licensee_code = &rom_data[NEW_LICENSEE_CODE.0..=NEW_LICENSEE_CODE.1];
-    println!(
-        "New licensee code: {}",
-        u8_array_to_string(new_licensee_code)
-    );
-    let old_licensee_code = &rom_data[OLD_LICENSEE_CODE.0..=OLD_LICENSEE_CODE.1];
-    println!(
-        "Old licensee code: {}",
-        u8_array_to_string(old_licensee_code)
-    );
-    let cartridge_type = &rom_data[CARTRIDGE_TYPE.0..=CARTRIDGE_TYPE.1];
-    println!("Cartridge type: {}", u8_array_to_string(cartridge_type));
-    let rom_size = &rom_data[ROM_SIZE.0..=ROM_SIZE.1];
-    println!("Rom size: {}", u8_array_to_string(rom_size));
-    let ram_size = &rom_data[RAM_SIZE.0..=RAM_SIZE.1];
-    println!("Ram size: {}", u8_array_to_string(ram_size));
-    let dest_code = &rom_data[DESTINATION_CODE.0 as usize..=DESTINATION_CODE.1 as usize];
-    println!("Destination code: {}", u8_array_to_string(dest_code));
-    let rom_version = &rom_data[ROM_VERSION_NUMBER.0 as usize..=ROM_VERSION_NUMBER.1 as usize];
-    println!("Rom version number: {}", u8_array_to_string(rom_version));
-    let header_checksum = &rom_data[HEADER_CHECKSUM.0 as usize..=HEADER_CHECKSUM.1 as usize];
-    println!("Header checksum: {}", u8_array_to_string(header_checksum));
-    let global_checksum = &rom_data[GLOBAL_CHECKSUM.0 as usize..=GLOBAL_CHECKSUM.1 as usize];
-    println!("Global checksum: {}", u8_array_to_string(global_checksum));
+/// Checksum of 0x0134-0x014C computed by the boot rom
+pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE.0..=ROM_VERSION_NUMBER.0]
+        .iter()
+        .fold(0u8, |checksum, &byte| {
+            checksum.wrapping_sub(byte).wrapping_sub(1)
+        })
+}
+/// Sum of every byte of the rom except the global checksum itself
+pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(address, _)| !(GLOBAL_CHECKSUM.0..=GLOBAL_CHECKSUM.1).contains(address))
+        .fold(0u16, |checksum, (_, &byte)| {
+            checksum.wrapping_add(byte as u16)
+        })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::util::cartridge_util::load;
+    use crate::util::cartridge_util::{
+        compute_global_checksum, compute_header_checksum, load, CGBSupport, CartridgeData,
+        CartridgeHeader, Destination, MBCType, NINTENDO_LOGO_BYTES,
+    };
+    use crate::util::error_type::Errors;
+
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0104..=0x0133].copy_from_slice(&NINTENDO_LOGO_BYTES);
+        rom[0x0134..0x0134 + 6].copy_from_slice(b"TETRIS");
+        rom[0x0147] = 0x13;
+        rom[0x0148] = 0x05;
+        rom[0x0149] = 0x03;
+        rom[0x014A] = 0x01;
+        rom[0x014B] = 0x33;
+        rom[0x0144..=0x0145].copy_from_slice(b"01");
+        rom[0x014C] = 0x01;
+        rom[0x014D] = compute_header_checksum(&rom);
+        let global = compute_global_checksum(&rom).to_be_bytes();
+        rom[0x014E..=0x014F].copy_from_slice(&global);
+        rom
+    }
 
     #[test]
     fn load_tetris() {
@@ -177,4 +339,38 @@ mod tests {
         let test = load("../roms/Tetris (JUE) (V1.1) [!].gb");
         let test = load("/home/anon/Documents/Code/GameBoyish/roms/cpu_instrs/06-ld r,r.gb");
     }
+    #[test]
+    fn parse_header_test() {
+        let header = CartridgeHeader::parse(&CartridgeData(test_rom())).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.licensee, "Nintendo R&D1");
+        assert_eq!(header.mbc_type, MBCType::MBC3_RAM_BATTERY_2);
+        assert_eq!(header.rom_banks, 64);
+        assert_eq!(header.ram_size, 0x8000);
+        assert_eq!(header.cgb_support, CGBSupport::DMGOnly);
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.version, 1);
+        assert!(header.logo_valid);
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+    }
+    #[test]
+    fn invalid_header_test() {
+        let mut rom = test_rom();
+        rom[0x0200] = 0xFF;
+        rom[0x0104] = 0x00;
+        let header = CartridgeHeader::parse(&CartridgeData(rom.clone())).unwrap();
+        assert!(!header.logo_valid);
+        assert!(!header.global_checksum_valid);
+        assert!(header.header_checksum_valid);
+        rom[0x0147] = 0x04;
+        assert!(matches!(
+            CartridgeHeader::parse(&CartridgeData(rom.clone())),
+            Err(Errors::InvalidMBCType(0x04))
+        ));
+        assert!(matches!(
+            CartridgeHeader::parse(&CartridgeData(rom[..0x0140].to_vec())),
+            Err(Errors::TruncatedRom(0x0140))
+        ));
+    }
 }
@@ -1,4 +1,6 @@
 use std::io;
+
+use crate::util::cartridge_util::MBCType;
 #[derive(Debug)]
 pub enum Errors {
     // Wrapped error from io::error
@@ -6,6 +8,19 @@ pub enum Errors {
     ErrorReadingFile(io::Error),
     SerdeJsonError(serde_json::Error),
     BusAccessError,
+    UnsupportedMBC(MBCType),
+    InvalidMBCType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    // Rom too short to contain the header, holds its length
+    TruncatedRom(usize),
+    InvalidPatch(String),
+    // (expected, actual) crc32
+    PatchChecksumMismatch(u32, u32),
+    InvalidGbs(String),
+    InvalidConfig(String),
+    InvalidArchive(String),
+    InvalidSaveState(String),
 }
 impl From<io::Error> for Errors {
     fn from(e: io::Error) -> Self {
@@ -17,3 +32,28 @@ impl From<serde_json::Error> for Errors {
         Errors::SerdeJsonError(e)
     }
 }
+impl std::fmt::Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::IOError(e) => write!(f, "{}", e),
+            Errors::ErrorReadingFile(e) => write!(f, "could not read file: {}", e),
+            Errors::SerdeJsonError(e) => write!(f, "invalid json: {}", e),
+            Errors::BusAccessError => write!(f, "invalid bus access"),
+            Errors::UnsupportedMBC(mbc_type) => write!(f, "unsupported mbc {:?}", mbc_type),
+            Errors::InvalidMBCType(value) => write!(f, "invalid mbc type {:#04x}", value),
+            Errors::InvalidRomSize(value) => write!(f, "invalid rom size {:#04x}", value),
+            Errors::InvalidRamSize(value) => write!(f, "invalid ram size {:#04x}", value),
+            Errors::TruncatedRom(len) => write!(f, "rom too short for a header ({} bytes)", len),
+            Errors::InvalidPatch(message) => write!(f, "invalid patch: {}", message),
+            Errors::PatchChecksumMismatch(expected, actual) => write!(
+                f,
+                "patch checksum mismatch, expected {:#010x} got {:#010x}",
+                expected, actual
+            ),
+            Errors::InvalidGbs(message) => write!(f, "invalid gbs file: {}", message),
+            Errors::InvalidConfig(message) => write!(f, "invalid config: {}", message),
+            Errors::InvalidArchive(message) => write!(f, "invalid archive: {}", message),
+            Errors::InvalidSaveState(message) => write!(f, "invalid save state: {}", message),
+        }
+    }
+}
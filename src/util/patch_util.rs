@@ -0,0 +1,389 @@
+use crate::util::{archive_util::MAX_ROM_SIZE, error_type::Errors};
+
+// Every UPS and BPS patch ends with the crc32 of the source, target and patch
+const CHECKSUMS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    IPS,
+    UPS,
+    BPS,
+}
+impl PatchFormat {
+    /// All the formats, in the order their file extension is looked up
+    pub const ALL: [PatchFormat; 3] = [PatchFormat::IPS, PatchFormat::UPS, PatchFormat::BPS];
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PatchFormat::IPS => "ips",
+            PatchFormat::UPS => "ups",
+            PatchFormat::BPS => "bps",
+        }
+    }
+}
+
+/// Return the patched rom, the original is left untouched
+pub fn apply_patch(format: PatchFormat, rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Errors> {
+    match format {
+        PatchFormat::IPS => apply_ips(rom, patch),
+        PatchFormat::UPS => apply_ups(rom, patch),
+        PatchFormat::BPS => apply_bps(rom, patch),
+    }
+}
+
+/// Read sequentially a patch file, any read past the end is an invalid patch
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8]) -> PatchReader<'a> {
+        PatchReader { data, position: 0 }
+    }
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], Errors> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(Errors::InvalidPatch("unexpected end of patch".to_string()))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+    fn byte(&mut self) -> Result<u8, Errors> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u16_be(&mut self) -> Result<usize, Errors> {
+        let bytes = self.bytes(2)?;
+        Ok(((bytes[0] as usize) << 8) | bytes[1] as usize)
+    }
+    fn u24_be(&mut self) -> Result<usize, Errors> {
+        let bytes = self.bytes(3)?;
+        Ok(((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize)
+    }
+    // Variable length number used by UPS and BPS
+    fn varint(&mut self) -> Result<usize, Errors> {
+        let overflow = || Errors::InvalidPatch("number too big".to_string());
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.byte()?;
+            value = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|digit| value.checked_add(digit))
+                .ok_or_else(overflow)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+            value = value.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+    // Size of the patched rom, bounded before anything is allocated for it
+    fn target_size(&mut self) -> Result<usize, Errors> {
+        let size = self.varint()?;
+        if size > MAX_ROM_SIZE {
+            return Err(Errors::InvalidPatch(format!(
+                "target of {} bytes is too big for a rom",
+                size
+            )));
+        }
+        Ok(size)
+    }
+    fn expect_magic(&mut self, magic: &[u8]) -> Result<(), Errors> {
+        match self.bytes(magic.len()) {
+            Ok(bytes) if bytes == magic => Ok(()),
+            _ => Err(Errors::InvalidPatch(format!(
+                "missing {} header",
+                String::from_utf8_lossy(magic)
+            ))),
+        }
+    }
+}
+
+/// IPS: list of (offset, data) records, without any checksum
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Errors> {
+    let mut reader = PatchReader::new(patch);
+    reader.expect_magic(b"PATCH")?;
+    let mut output = rom.to_vec();
+    loop {
+        if reader.bytes(3)? == b"EOF" {
+            break;
+        }
+        reader.position -= 3;
+        let offset = reader.u24_be()?;
+        let size = reader.u16_be()?;
+        // Size 0 is a run length encoded record
+        let data = match size {
+            0 => {
+                let length = reader.u16_be()?;
+                vec![reader.byte()?; length]
+            }
+            _ => reader.bytes(size)?.to_vec(),
+        };
+        if output.len() < offset + data.len() {
+            output.resize(offset + data.len(), 0);
+        }
+        output[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    // Optional truncation extension
+    if let Ok(length) = reader.u24_be() {
+        output.truncate(length);
+    }
+    Ok(output)
+}
+
+/// UPS: xor of the source and target, with crc32 checks
+pub fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Errors> {
+    let checksums = verify_patch_checksum(patch)?;
+    let mut reader = PatchReader::new(&patch[..patch.len() - CHECKSUMS_FOOTER_SIZE]);
+    reader.expect_magic(b"UPS1")?;
+    let source_size = reader.varint()?;
+    let target_size = reader.target_size()?;
+    check_crc32(rom, source_size, checksums.0)?;
+
+    let mut output = rom.to_vec();
+    output.resize(target_size, 0);
+    let out_of_bounds = || Errors::InvalidPatch("xor out of bounds".to_string());
+    let mut offset = 0usize;
+    while reader.position < reader.data.len() {
+        offset = offset
+            .checked_add(reader.varint()?)
+            .ok_or_else(out_of_bounds)?;
+        // Xor until a 0 byte which stands for an unchanged byte
+        loop {
+            let byte = reader.byte()?;
+            if let Some(target) = output.get_mut(offset) {
+                *target ^= byte;
+            }
+            offset = offset.checked_add(1).ok_or_else(out_of_bounds)?;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+    check_crc32(&output, target_size, checksums.1)?;
+    Ok(output)
+}
+
+/// BPS: copy commands from the source, the patch or the target, with crc32 checks
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Errors> {
+    let checksums = verify_patch_checksum(patch)?;
+    let mut reader = PatchReader::new(&patch[..patch.len() - CHECKSUMS_FOOTER_SIZE]);
+    reader.expect_magic(b"BPS1")?;
+    let source_size = reader.varint()?;
+    let target_size = reader.target_size()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    check_crc32(rom, source_size, checksums.0)?;
+
+    let invalid = || Errors::InvalidPatch("copy out of bounds".to_string());
+    let mut output: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    while reader.position < reader.data.len() {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        if length > target_size - output.len() {
+            return Err(invalid());
+        }
+        match data & 0x03 {
+            // Source read
+            0 => {
+                let start = output.len();
+                let bytes = start
+                    .checked_add(length)
+                    .and_then(|end| rom.get(start..end))
+                    .ok_or_else(invalid)?;
+                output.extend_from_slice(bytes);
+            }
+            // Target read
+            1 => output.extend_from_slice(reader.bytes(length)?),
+            // Source copy
+            2 => {
+                source_offset = source_offset
+                    .checked_add(read_signed_offset(&mut reader)?)
+                    .ok_or_else(invalid)?;
+                let start = usize::try_from(source_offset).map_err(|_| invalid())?;
+                let bytes = start
+                    .checked_add(length)
+                    .and_then(|end| rom.get(start..end))
+                    .ok_or_else(invalid)?;
+                output.extend_from_slice(bytes);
+                source_offset += length as isize;
+            }
+            // Target copy, byte per byte because it can overlap what it writes
+            _ => {
+                target_offset = target_offset
+                    .checked_add(read_signed_offset(&mut reader)?)
+                    .ok_or_else(invalid)?;
+                for _ in 0..length {
+                    let index = usize::try_from(target_offset).map_err(|_| invalid())?;
+                    let byte = *output.get(index).ok_or_else(invalid)?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if output.len() != target_size {
+        return Err(Errors::InvalidPatch("wrong target size".to_string()));
+    }
+    check_crc32(&output, target_size, checksums.1)?;
+    Ok(output)
+}
+
+fn read_signed_offset(reader: &mut PatchReader) -> Result<isize, Errors> {
+    let data = reader.varint()?;
+    let offset = (data >> 1) as isize;
+    Ok(if data & 1 == 1 { -offset } else { offset })
+}
+
+// Check the crc32 of the patch itself and return the (source, target) crc32
+fn verify_patch_checksum(patch: &[u8]) -> Result<(u32, u32), Errors> {
+    if patch.len() < CHECKSUMS_FOOTER_SIZE + 4 {
+        return Err(Errors::InvalidPatch("patch too short".to_string()));
+    }
+    let footer = patch.len() - CHECKSUMS_FOOTER_SIZE;
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(patch[offset..offset + 4].try_into().unwrap());
+    let expected = read_u32(footer + 8);
+    let actual = crc32(&patch[..footer + 8]);
+    if expected != actual {
+        return Err(Errors::PatchChecksumMismatch(expected, actual));
+    }
+    Ok((read_u32(footer), read_u32(footer + 4)))
+}
+
+fn check_crc32(data: &[u8], size: usize, expected: u32) -> Result<(), Errors> {
+    if data.len() != size {
+        return Err(Errors::InvalidPatch(format!(
+            "expected a {} bytes rom, got {} bytes",
+            size,
+            data.len()
+        )));
+    }
+    let actual = crc32(data);
+    if expected != actual {
+        return Err(Errors::PatchChecksumMismatch(expected, actual));
+    }
+    Ok(())
+}
+
+/// Standard crc32 (polynomial 0xEDB88320) as used by UPS, BPS and zip
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_bps, apply_ips, apply_ups, crc32};
+    use crate::util::error_type::Errors;
+
+    // Append the source, target and patch crc32 like UPS and BPS patches do
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+    #[test]
+    fn ips_test() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 0x000001
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // run of 3 times 0xCC at 0x000006, grows the rom
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        let output = apply_ips(&rom, &patch).unwrap();
+        assert_eq!(output, [0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC]);
+        assert!(matches!(
+            apply_ips(&rom, b"PATCH\x00\x00"),
+            Err(Errors::InvalidPatch(_))
+        ));
+    }
+    #[test]
+    fn ups_test() {
+        let source = [1u8, 2, 3, 4];
+        let target = [1u8, 7, 3, 4, 9];
+        // sizes 4 and 5, skip 1 byte, xor 2^7, end of hunk, skip 1 byte, xor 0^9
+        let patch = vec![
+            b'U', b'P', b'S', b'1', 0x84, 0x85, 0x81, 0x05, 0x00, 0x81, 0x09, 0x00,
+        ];
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply_ups(&source, &patch).unwrap(), target);
+        // Wrong source rom
+        assert!(matches!(
+            apply_ups(&[1u8, 2, 3, 5], &patch),
+            Err(Errors::PatchChecksumMismatch(_, _))
+        ));
+        // Corrupted patch
+        let mut corrupted = patch.clone();
+        corrupted[7] = 0x06;
+        assert!(matches!(
+            apply_ups(&source, &corrupted),
+            Err(Errors::PatchChecksumMismatch(_, _))
+        ));
+    }
+    #[test]
+    fn bps_test() {
+        let source = [1u8, 2, 3, 4];
+        let target = [1u8, 2, 9, 9, 9, 1, 2];
+        let mut patch = b"BPS1".to_vec();
+        // sizes 4 and 7, no metadata
+        patch.extend_from_slice(&[0x84, 0x87, 0x80]);
+        // source read 2 bytes
+        patch.extend_from_slice(&[0x84]);
+        // target read 1 byte
+        patch.extend_from_slice(&[0x81, 0x09]);
+        // target copy 2 bytes from offset 2
+        patch.extend_from_slice(&[0x87, 0x84]);
+        // source copy 2 bytes from offset 0
+        patch.extend_from_slice(&[0x86, 0x80]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply_bps(&source, &patch).unwrap(), target);
+    }
+    #[test]
+    fn overflow_test() {
+        let source = [1u8, 2, 3, 4];
+        let patch = |header: &[u8]| with_footer(header.to_vec(), &source, &source);
+        let invalid = |result| matches!(result, Err(Errors::InvalidPatch(_)));
+        // Command number that doesn't fit in 64 bits
+        let mut bps = b"BPS1\x84\x84\x80".to_vec();
+        bps.extend_from_slice(&[0x7F; 9]);
+        bps.push(0xFF);
+        assert!(invalid(apply_bps(&source, &patch(&bps))));
+        // 16 MiB target, bigger than any rom
+        assert!(invalid(apply_bps(
+            &source,
+            &patch(b"BPS1\x84\x00\x7F\x7E\x86\x80")
+        )));
+        assert!(invalid(apply_ups(
+            &source,
+            &patch(b"UPS1\x84\x00\x7F\x7E\x86")
+        )));
+        // Source copy past the end of the address space
+        let mut bps = b"BPS1\x84\x84\x80\x82".to_vec();
+        bps.extend_from_slice(&[0x7E; 8]);
+        bps.push(0xBE);
+        assert!(invalid(apply_bps(&source, &patch(&bps))));
+        // Target read longer than the target
+        assert!(invalid(apply_bps(&source, &patch(b"BPS1\x84\x84\x80\x91"))));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::util::error_type::Errors;
+
+const WAV_HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// 16-bit stereo PCM wav file, the sizes in the header are written by `finish`
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_size: u32,
+}
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<WavWriter, Errors> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+    /// Samples are clamped between -1.0 and 1.0
+    pub fn write_sample(&mut self, left: f32, right: f32) -> Result<(), Errors> {
+        self.writer.write_all(&to_i16(left).to_le_bytes())?;
+        self.writer.write_all(&to_i16(right).to_le_bytes())?;
+        self.data_size += (CHANNELS * BITS_PER_SAMPLE / 8) as u32;
+        Ok(())
+    }
+    pub fn finish(mut self) -> Result<(), Errors> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(WAV_HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::WavWriter;
+
+    #[test]
+    fn wav_writer_test() {
+        let path = env::temp_dir().join("game_boyish_wav_writer_test.wav");
+        let mut wav = WavWriter::create(&path, 48_000).unwrap();
+        wav.write_sample(1.0, -1.0).unwrap();
+        wav.write_sample(0.0, 2.0).unwrap();
+        wav.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 48_000);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        assert_eq!(&data[44..48], &[0xFF, 0x7F, 0x01, 0x80]);
+        assert_eq!(&data[50..52], &[0xFF, 0x7F]);
+        fs::remove_file(&path).unwrap();
+    }
+}
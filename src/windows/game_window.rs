@@ -1,6 +1,6 @@
 use std::time::Instant;
 
-use minifb::{MouseMode, Window, WindowOptions};
+use minifb::{Key, MouseMode, Window, WindowOptions};
 use raqote::{DrawOptions, DrawTarget, PathBuilder, SolidSource, Source};
 
 use crate::util::tiles_util::ScreenVector;
@@ -13,6 +13,8 @@ pub struct GameWindow {
     window: Option<Window>,
     refresh_rate_delta: usize,
     last_refresh: Instant,
+    // Colors of the 4 shades drawn instead of the grays
+    palette: Option<[u32; 4]>,
 }
 impl GameWindow {
     pub fn new(width: usize, height: usize) -> GameWindow {
@@ -22,8 +24,17 @@ impl GameWindow {
             window: None,
             refresh_rate_delta: 16,
             last_refresh: Instant::now(),
+            palette: None,
         }
     }
+    /// Size of the console screen times the scale, to call before `init`
+    pub fn set_scale(&mut self, scale: usize) {
+        self.width = GAMEBOY_SCREEN_WIDTH * scale;
+        self.height = GAMEBOY_SCREEN_HEIGHT * scale;
+    }
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = Some(palette);
+    }
     pub fn init(&mut self, name: &str, resize: bool) {
         let window = Window::new(
             name,
@@ -38,6 +49,17 @@ impl GameWindow {
         self.window = Some(window);
     }
 
+    // False once the user closed the window, always true before `init`
+    pub fn is_open(&self) -> bool {
+        self.window.as_ref().is_none_or(|window| window.is_open())
+    }
+    // Keys held down, updated each time the window is drawn
+    pub fn get_keys(&self) -> Vec<Key> {
+        match &self.window {
+            Some(window) => window.get_keys(),
+            None => vec![],
+        }
+    }
     pub fn next_tick(&mut self, buffer: &ScreenVector) {
         if self.last_refresh.elapsed().as_millis() >= self.refresh_rate_delta as u128 {
             // println!("refresh: {:?}", self.last_refresh.elapsed());
@@ -50,8 +72,16 @@ impl GameWindow {
         // let vec = array.concat();
         if let Some(window) = &mut self.window {
             let size = window.get_size();
+            let pixels = match self.palette {
+                Some(palette) => buffer
+                    .pixelcolor_vec
+                    .iter()
+                    .map(|&pixel| palette[shade_index(pixel)])
+                    .collect(),
+                None => buffer.pixelcolor_vec.clone(),
+            };
             window
-                .update_with_buffer(&buffer.pixelcolor_vec, buffer.width, buffer.height())
+                .update_with_buffer(&pixels, buffer.width, buffer.height())
                 .unwrap();
             // window
             //     .update_with_buffer(
@@ -73,6 +103,15 @@ fn random_buffer(width: usize, height: usize) -> Vec<u32> {
     }
     buffer
 }
+// Shade of a gray drawn by the ppu, 0 being the lightest
+fn shade_index(pixel: u32) -> usize {
+    match pixel & 0xFF {
+        0xD0..=0xFF => 0,
+        0x70..=0xCF => 1,
+        0x01..=0x6F => 2,
+        _ => 3,
+    }
+}
 pub fn from_u32_gray_to_rgb(gray: u32) -> u32 {
     let (r, g, b) = (gray, gray, gray);
     (r << 16) | (g << 8) | b
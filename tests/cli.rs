@@ -0,0 +1,22 @@
+use std::{env, fs, process::Command};
+
+// Writes 0x42 to 0xC000 then loops on itself
+fn test_rom() -> Vec<u8> {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x0100..0x0107].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+    rom
+}
+
+#[test]
+fn headless_quiet_test() {
+    let rom = env::temp_dir().join("game_boyish_headless_quiet_test.gb");
+    fs::write(&rom, test_rom()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_game_boyish"))
+        .args(["--headless", "--frames", "2", "--config", "missing.json"])
+        .arg(&rom)
+        .output()
+        .unwrap();
+    fs::remove_file(&rom).unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+}